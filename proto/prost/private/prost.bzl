@@ -267,6 +267,7 @@ def _rust_prost_aspect_impl(target, ctx):
         deps = rust_analyzer_deps,
         crate_specs = depset(transitive = [dep.crate_specs for dep in rust_analyzer_deps]),
         proc_macro_dylib_path = None,
+        proc_macro_dylibs = depset(transitive = [dep.proc_macro_dylibs for dep in rust_analyzer_deps]),
         build_info = dep_variant_info.build_info,
     ))
 
@@ -165,6 +165,7 @@ RustAnalyzerInfo = provider(
         "deps": "List[RustAnalyzerInfo]: direct dependencies",
         "env": "Dict[String: String]: Environment variables, used for the `env!` macro",
         "proc_macro_dylib_path": "File: compiled shared library output of proc-macro rule",
+        "proc_macro_dylibs": "Depset[File]: compiled shared libraries of this crate and its transitive proc-macro dependencies",
     },
 )
 
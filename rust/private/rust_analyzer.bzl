@@ -55,6 +55,7 @@ def write_rust_analyzer_spec_file(ctx, attrs, owner, base_info):
         deps = base_info.deps,
         crate_specs = depset(direct = [crate_spec], transitive = [base_info.crate_specs]),
         proc_macro_dylib_path = base_info.proc_macro_dylib_path,
+        proc_macro_dylibs = base_info.proc_macro_dylibs,
         build_info = base_info.build_info,
     )
 
@@ -143,12 +144,21 @@ def _rust_analyzer_aspect_impl(target, ctx):
         deps = dep_infos,
         crate_specs = depset(transitive = [dep.crate_specs for dep in dep_infos]),
         proc_macro_dylib_path = find_proc_macro_dylib_path(toolchain, target),
+        # Proc-macro deps are built in the exec configuration, so collecting their
+        # outputs here lets `rust_binary` roots build every dylib rust-analyzer loads.
+        proc_macro_dylibs = depset(
+            direct = [crate_info.output] if crate_info.type == "proc-macro" else [],
+            transitive = [dep.proc_macro_dylibs for dep in dep_infos],
+        ),
         build_info = build_info,
     ))
 
     return [
         rust_analyzer_info,
-        OutputGroupInfo(rust_analyzer_crate_spec = rust_analyzer_info.crate_specs),
+        OutputGroupInfo(
+            rust_analyzer_crate_spec = rust_analyzer_info.crate_specs,
+            rust_analyzer_proc_macro_dylib = rust_analyzer_info.proc_macro_dylibs,
+        ),
     ]
 
 def find_proc_macro_dylib_path(toolchain, target):
@@ -214,6 +224,7 @@ def _create_single_crate(ctx, attrs, info):
     crate_id = _crate_id(info.crate)
     crate["crate_id"] = crate_id
     crate["display_name"] = crate_name
+    crate["bazel_target"] = str(ctx.label)
     crate["edition"] = info.crate.edition
     crate["env"] = {}
     crate["crate_type"] = info.crate.type
@@ -263,6 +274,14 @@ def _create_single_crate(ctx, attrs, info):
     crate["deps"] = [_crate_id(dep.crate) for dep in info.deps if _crate_id(dep.crate) != crate_id]
     crate["aliases"] = {_crate_id(alias_target.crate): alias_name for alias_target, alias_name in info.aliases.items()}
     crate["cfg"] = info.cfgs
+
+    # Tools consuming rust-project.json may want the crate's other rustc flags too. Its
+    # `--cfg` flags are also part of `cfg`, the only ones rust-analyzer understands.
+    # Expanded like rustc actions expand them, so they don't carry `$(location ...)` or `$(VAR)`.
+    crate["rustc_flags"] = [
+        ctx.expand_make_variables("rustc_flags", dedup_expand_location(ctx, f, expand_targets), {})
+        for f in getattr(attrs, "rustc_flags", [])
+    ]
     crate["target"] = find_toolchain(ctx).target_flag_value
     if info.proc_macro_dylib_path != None:
         crate["proc_macro_dylib_path"] = _EXEC_ROOT_TEMPLATE + info.proc_macro_dylib_path
@@ -341,8 +360,33 @@ def _rust_analyzer_detect_sysroot_impl(ctx):
 
     return [DefaultInfo(files = depset([output]))]
 
+def _compilation_mode_transition_impl(settings, attr):
+    return {
+        "//command_line_option:compilation_mode": attr.compilation_mode or settings["//command_line_option:compilation_mode"],
+    }
+
+_compilation_mode_transition = transition(
+    implementation = _compilation_mode_transition_impl,
+    inputs = [
+        "//command_line_option:compilation_mode",
+    ],
+    outputs = [
+        "//command_line_option:compilation_mode",
+    ],
+)
+
 rust_analyzer_detect_sysroot = rule(
     implementation = _rust_analyzer_detect_sysroot_impl,
+    attrs = {
+        "compilation_mode": attr.string(
+            doc = "Detect the sysroot of the toolchain selected in this compilation mode instead of the current one.",
+            values = ["", "dbg", "fastbuild", "opt"],
+        ),
+        "_allowlist_function_transition": attr.label(
+            default = "@bazel_tools//tools/allowlists/function_transition_allowlist",
+        ),
+    },
+    cfg = _compilation_mode_transition,
     toolchains = [
         "@rules_rust//rust:toolchain_type",
         "@rules_rust//rust/rust_analyzer:toolchain_type",
@@ -30,4 +30,22 @@ mod tests {
             content.contains(expected),
             "expected rust-project.json to contain both lib_dep and extra_test_dep in deps of mylib.rs.");
     }
+
+    #[test]
+    fn test_rustc_flags_of_crate_and_its_test_are_merged() {
+        let rust_project_path = PathBuf::from(env::var("RUST_PROJECT_JSON").unwrap());
+
+        let content = std::fs::read_to_string(&rust_project_path)
+            .unwrap_or_else(|_| panic!("couldn't open {:?}", &rust_project_path));
+
+        let expected = r#""rustc_flags": [
+        "-Cdebug-assertions=on"
+      ],"#;
+
+        println!("{}", content);
+        assert!(
+            content.contains(expected),
+            "expected rust-project.json to contain the rustc_flags of mylib_test in mylib.rs."
+        );
+    }
 }
@@ -1,20 +1,29 @@
-use std::collections::{BTreeMap, BTreeSet};
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
+use std::time::SystemTime;
 
 use anyhow::Context;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+// Empty lists are omitted from the `jsonproto` output.
 #[derive(Debug, Deserialize)]
 struct AqueryOutput {
+    #[serde(default)]
     artifacts: Vec<Artifact>,
+    #[serde(default)]
     actions: Vec<Action>,
-    #[serde(rename = "pathFragments")]
+    #[serde(default)]
+    targets: Vec<Target>,
+    #[serde(default, rename = "pathFragments")]
     path_fragments: Vec<PathFragment>,
 }
 
+#[derive(Debug, Deserialize)]
+struct Target {
+    label: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct Artifact {
     id: u32,
@@ -36,12 +45,16 @@ struct Action {
     output_ids: Vec<u32>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct CrateSpec {
     pub aliases: BTreeMap<String, String>,
     pub crate_id: String,
     pub display_name: String,
+    /// The label of the target that produced the crate. Empty for specs written by
+    /// older aspects.
+    #[serde(default)]
+    pub bazel_target: String,
     pub edition: String,
     pub root_module: String,
     pub is_workspace_member: bool,
@@ -52,67 +65,503 @@ pub struct CrateSpec {
     pub env: BTreeMap<String, String>,
     pub target: String,
     pub crate_type: String,
+    /// The `rustc_flags` of the crate's target. Absent from specs written by older
+    /// aspects.
+    #[serde(default)]
+    pub rustc_flags: Vec<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct CrateSpecSource {
     pub exclude_dirs: Vec<String>,
     pub include_dirs: Vec<String>,
 }
 
+/// Options controlling how crate specs sharing a `crate_id` are consolidated.
+#[derive(Clone, Debug, Default)]
+pub struct ConsolidationOptions {
+    /// Fail instead of warning when specs sharing a `crate_id` disagree on
+    /// metadata that can't be meaningfully merged, such as `edition`.
+    pub strict: bool,
+
+    /// Which crate specs are considered the same crate and merged together.
+    pub key: ConsolidationKey,
+}
+
+/// Identifies crate specs that describe the same crate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConsolidationKey {
+    /// Merge specs with the same `crate_id`, i.e. the same root module path.
+    #[default]
+    CrateId,
+
+    /// Merge specs with the same `root_module` and `display_name`, even if their
+    /// `crate_id`s differ. This merges variants of a crate produced by different
+    /// configurations, but can over-merge unrelated crates that happen to share
+    /// both, combining their deps and cfgs into one crate.
+    RootModuleAndDisplayName,
+}
+
+impl ConsolidationKey {
+    /// Returns the key `spec` is consolidated under.
+    fn of(&self, spec: &CrateSpec) -> String {
+        match self {
+            ConsolidationKey::CrateId => spec.crate_id.clone(),
+            ConsolidationKey::RootModuleAndDisplayName => {
+                format!("{} ({})", spec.display_name, spec.root_module)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ConsolidationKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsolidationKey::CrateId => write!(f, "crate_id"),
+            ConsolidationKey::RootModuleAndDisplayName => write!(f, "root_module and display_name"),
+        }
+    }
+}
+
+/// Accumulates crate specs from one or more aquery runs so they can be
+/// consolidated together. This allows large workspaces to be queried in
+/// several smaller scopes instead of one large aquery.
+#[derive(Debug, Default)]
+pub struct CrateSpecCollector {
+    crate_specs: Vec<CrateSpec>,
+    execution_root: Option<PathBuf>,
+}
+
+impl CrateSpecCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the crate specs referenced by the given aquery output. If any of them
+    /// fail to parse, the collector is left unchanged so the caller may retry or
+    /// skip that scope.
+    pub fn add_aquery_output(
+        &mut self,
+        execution_root: &Path,
+        aquery_stdout: &str,
+    ) -> anyhow::Result<()> {
+        let crate_spec_files = parse_aquery_output_files(execution_root, aquery_stdout)?;
+
+        let crate_specs = read_crate_specs(&crate_spec_files)?;
+
+        self.crate_specs.extend(crate_specs);
+        self.execution_root = Some(execution_root.to_owned());
+
+        Ok(())
+    }
+
+    /// Consolidates all collected crate specs.
+    pub fn finish(self, options: &ConsolidationOptions) -> anyhow::Result<BTreeSet<CrateSpec>> {
+        consolidate_crate_specs(self.crate_specs, options, self.execution_root.as_deref())
+    }
+}
+
+/// Reads and deserializes `files` in parallel, returning the crate specs in the
+/// same order as `files`.
+fn read_crate_specs(files: &[PathBuf]) -> anyhow::Result<Vec<CrateSpec>> {
+    let read = |file: &PathBuf| -> anyhow::Result<CrateSpec> {
+        let content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to open file: {}", file.display()))?;
+        serde_json::from_str(&content).map_err(|err| crate_spec_error(file, &content, err))
+    };
+
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = files.len().div_ceil(threads).max(1);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || chunk.iter().map(read).collect::<anyhow::Result<Vec<_>>>())
+            })
+            .collect();
+
+        let mut crate_specs = Vec::with_capacity(files.len());
+        for handle in handles {
+            crate_specs.extend(handle.join().expect("crate spec reader panicked")?);
+        }
+        Ok(crate_specs)
+    })
+}
+
+/// Describes why the crate spec in `file` failed to deserialize. Unknown fields are
+/// usually caused by an aspect from a different rules_rust version than the tool, so
+/// they are reported along with the spec's target to make the mismatch obvious.
+fn crate_spec_error(file: &Path, content: &str, err: serde_json::Error) -> anyhow::Error {
+    let message = err.to_string();
+    let unknown_field = message
+        .strip_prefix("unknown field `")
+        .and_then(|rest| rest.split_once('`'))
+        .map(|(field, _)| field.to_owned());
+    let Some(field) = unknown_field else {
+        return anyhow::Error::new(err)
+            .context(format!("Failed to deserialize file: {}", file.display()));
+    };
+
+    let spec = serde_json::from_str::<serde_json::Value>(content).unwrap_or_default();
+    let name = ["bazel_target", "crate_id"]
+        .iter()
+        .find_map(|key| spec.get(key).and_then(|v| v.as_str()))
+        .map(|name| format!("{name} "))
+        .unwrap_or_default();
+    anyhow::anyhow!(
+        "crate spec {name}(file {}) has unexpected field `{field}`, your aspect and tool versions may be mismatched",
+        file.display()
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn get_crate_specs(
     bazel: &Path,
     workspace: &Path,
     execution_root: &Path,
     targets: &[String],
-    rules_rust_name: &str,
+    aspect: &str,
+    compilation_mode: Option<&str>,
+    extra_args: &[String],
+    no_deps: bool,
+    options: &ConsolidationOptions,
 ) -> anyhow::Result<BTreeSet<CrateSpec>> {
-    log::debug!("Get crate specs with targets: {:?}", targets);
+    if targets.is_empty() {
+        return Err(anyhow::anyhow!(
+            "no targets provided to generate crate info"
+        ));
+    }
+
+    let mut collector = CrateSpecCollector::new();
+    collector.add_aquery_output(
+        execution_root,
+        &run_aquery(
+            bazel,
+            workspace,
+            targets,
+            aspect,
+            compilation_mode,
+            extra_args,
+            no_deps,
+        )?,
+    )?;
+    collector.finish(options)
+}
+
+/// Reads and consolidates the crate spec files found under `dir`, e.g. a copy of
+/// `bazel-bin` from an earlier build, without running Bazel. This allows
+/// `rust-project.json` to be generated where `bazel aquery` can't be run.
+pub fn get_crate_specs_from_dir(
+    dir: &Path,
+    execution_root: &Path,
+    options: &ConsolidationOptions,
+) -> anyhow::Result<BTreeSet<CrateSpec>> {
+    let mut crate_spec_files = Vec::new();
+    collect_crate_spec_files(dir, &mut crate_spec_files)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    crate_spec_files.sort();
+    log::debug!(
+        "Found {} crate spec files in {}",
+        crate_spec_files.len(),
+        dir.display()
+    );
+
+    consolidate_crate_specs(
+        read_crate_specs(&crate_spec_files)?,
+        options,
+        Some(execution_root),
+    )
+}
+
+/// Reads and consolidates the crate spec `files`, e.g. those reported by the build
+/// that generated them. Relative paths are relative to `execution_root`.
+pub fn get_crate_specs_from_files(
+    files: &[PathBuf],
+    execution_root: &Path,
+    options: &ConsolidationOptions,
+) -> anyhow::Result<BTreeSet<CrateSpec>> {
+    let files: Vec<PathBuf> = files.iter().map(|f| execution_root.join(f)).collect();
+    consolidate_crate_specs(read_crate_specs(&files)?, options, Some(execution_root))
+}
+
+/// Recursively collects the paths of the crate spec files under `dir`.
+fn collect_crate_spec_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            collect_crate_spec_files(&path, files)?;
+        } else if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".rust_analyzer_crate_spec.json")
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Identifies cached crate specs. See [crate_specs_cache_key].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrateSpecsCacheKey {
+    /// Identifies the request: the workspace, targets and options. Each request has
+    /// a single cache entry, replaced whenever its inputs change.
+    pub request: u64,
+
+    /// Fingerprints the build inputs the crate specs may depend on.
+    pub inputs: u64,
+}
+
+/// A cache entry written by [cached_crate_specs].
+#[derive(Deserialize, Serialize)]
+struct CrateSpecsCacheEntry {
+    inputs: u64,
+    crate_specs: BTreeSet<CrateSpec>,
+}
+
+/// Returns the crate specs cached in `cache_dir` under `key`, or computes them with
+/// `get` and caches the result. When `refresh` is set the cache is ignored and
+/// overwritten. A cache that can't be read or written is treated as a miss.
+pub fn cached_crate_specs(
+    cache_dir: &Path,
+    key: CrateSpecsCacheKey,
+    refresh: bool,
+    get: impl FnOnce() -> anyhow::Result<BTreeSet<CrateSpec>>,
+) -> anyhow::Result<BTreeSet<CrateSpec>> {
+    let cache_file = cache_dir.join(format!("crate_specs-{:016x}.json", key.request));
+
+    if !refresh {
+        match std::fs::read(&cache_file) {
+            Ok(content) => match serde_json::from_slice::<CrateSpecsCacheEntry>(&content) {
+                Ok(entry) if entry.inputs == key.inputs => {
+                    log::debug!("Using cached crate specs from {}", cache_file.display());
+                    return Ok(entry.crate_specs);
+                }
+                Ok(_) => log::debug!("Crate spec cache {} is stale", cache_file.display()),
+                Err(err) => log::debug!(
+                    "Ignoring unreadable crate spec cache {}: {}",
+                    cache_file.display(),
+                    err
+                ),
+            },
+            Err(_) => log::debug!("No crate spec cache at {}", cache_file.display()),
+        }
+    }
+
+    let entry = CrateSpecsCacheEntry {
+        inputs: key.inputs,
+        crate_specs: get()?,
+    };
+
+    let written = std::fs::create_dir_all(cache_dir)
+        .and_then(|_| std::fs::write(&cache_file, serde_json::to_vec(&entry)?));
+    if let Err(err) = written {
+        log::warn!(
+            "Failed to write crate spec cache {}: {}",
+            cache_file.display(),
+            err
+        );
+    }
+
+    Ok(entry.crate_specs)
+}
+
+/// Computes a cache key for the crate specs of `targets` in `workspace`. The request
+/// covers the workspace, the (order insensitive) targets and `extra`. The inputs
+/// cover the modification times of the files in the workspace that can change the
+/// build graph: BUILD, WORKSPACE and MODULE files, their lock files, `.bzl` files
+/// and Cargo manifests and lock files read by crate_universe. Directories listed in
+/// `.bazelignore` are skipped, as Bazel skips them.
+pub fn crate_specs_cache_key(
+    workspace: &Path,
+    targets: &[String],
+    extra: &str,
+) -> CrateSpecsCacheKey {
+    let mut targets: Vec<&String> = targets.iter().collect();
+    targets.sort();
+    targets.dedup();
+
+    let mut request = StableHasher::new();
+    request.write(workspace.to_string_lossy().as_bytes());
+    for target in targets {
+        request.write(target.as_bytes());
+    }
+    request.write(extra.as_bytes());
+
+    let ignored = bazelignore(workspace);
+    let mut build_inputs = Vec::new();
+    collect_build_input_mtimes(workspace, &ignored, &mut build_inputs);
+    build_inputs.sort();
+
+    let mut inputs = StableHasher::new();
+    for (path, modified) in build_inputs {
+        inputs.write(path.to_string_lossy().as_bytes());
+        inputs.write(&modified.to_le_bytes());
+    }
+
+    CrateSpecsCacheKey {
+        request: request.finish(),
+        inputs: inputs.finish(),
+    }
+}
+
+/// A 64-bit FNV-1a hash. Unlike [std::collections::hash_map::DefaultHasher], its
+/// output doesn't change between Rust releases, so it can key data kept on disk.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Hashes `bytes`, prefixed with their length so consecutive values can't run
+    /// into each other.
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Returns the directories listed in the `.bazelignore` file of `workspace`.
+fn bazelignore(workspace: &Path) -> Vec<PathBuf> {
+    let Ok(content) = std::fs::read_to_string(workspace.join(".bazelignore")) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .map(|line| line.trim().trim_end_matches('/'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| workspace.join(line))
+        .collect()
+}
+
+/// Recursively collects the paths and modification times, in nanoseconds since the
+/// Unix epoch, of the build inputs under `dir`. Hidden directories, the `bazel-*`
+/// convenience symlinks and the `ignored` directories are skipped.
+fn collect_build_input_mtimes(
+    dir: &Path,
+    ignored: &[PathBuf],
+    build_inputs: &mut Vec<(PathBuf, u128)>,
+) {
+    const BUILD_INPUTS: [&str; 11] = [
+        "BUILD",
+        "BUILD.bazel",
+        "WORKSPACE",
+        "WORKSPACE.bazel",
+        "WORKSPACE.bzlmod",
+        "MODULE.bazel",
+        "MODULE.bazel.lock",
+        ".bazelrc",
+        "Cargo.toml",
+        "Cargo.lock",
+        "cargo-bazel-lock.json",
+    ];
+
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            if !name.starts_with('.') && !name.starts_with("bazel-") && !ignored.contains(&path) {
+                collect_build_input_mtimes(&path, ignored, build_inputs);
+            }
+        } else if BUILD_INPUTS.contains(&name.as_ref()) || name.ends_with(".bzl") {
+            let modified = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok());
+            if let Some(modified) = modified {
+                build_inputs.push((path, modified.as_nanos()));
+            }
+        }
+    }
+}
+
+/// Returns the aquery expression selecting the crate spec outputs of `targets` and,
+/// unless `no_deps` is set, their dependencies. Targets are deduplicated and sorted
+/// so that identical requests produce identical queries.
+fn crate_spec_query(targets: &[String], no_deps: bool) -> String {
     let target_pattern = targets
         .iter()
-        .map(|t| format!("deps({t})"))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|t| {
+            if no_deps {
+                t.clone()
+            } else {
+                format!("deps({t})")
+            }
+        })
         .collect::<Vec<_>>()
         .join("+");
 
-    let aquery_output = Command::new(bazel)
-        .current_dir(workspace)
-        .env_remove("BAZELISK_SKIP_WRAPPER")
-        .env_remove("BUILD_WORKING_DIRECTORY")
-        .env_remove("BUILD_WORKSPACE_DIRECTORY")
+    format!(r#"outputs(".*\.rust_analyzer_crate_spec\.json",{target_pattern})"#)
+}
+
+/// Runs an aquery for the crate spec outputs of `targets` and, unless `no_deps` is
+/// set, their dependencies, returning the raw `jsonproto` output. `extra_args` are
+/// passed to `bazel aquery` before the query expression.
+pub fn run_aquery(
+    bazel: &Path,
+    workspace: &Path,
+    targets: &[String],
+    aspect: &str,
+    compilation_mode: Option<&str>,
+    extra_args: &[String],
+    no_deps: bool,
+) -> anyhow::Result<String> {
+    log::debug!("Get crate specs with targets: {:?}", targets);
+    let query = crate_spec_query(targets, no_deps);
+    let mut aquery_command = crate::bazel_command(bazel, Some(workspace));
+    aquery_command
         .arg("aquery")
+        .args(crate::BAZEL_OUTPUT_FLAGS)
         .arg("--include_aspects")
         .arg("--include_artifacts")
-        .arg(format!(
-            "--aspects={rules_rust_name}//rust:defs.bzl%rust_analyzer_aspect"
-        ))
+        .args(compilation_mode.map(|mode| format!("--compilation_mode={mode}")))
+        .arg(format!("--aspects={aspect}"))
         .arg("--output_groups=rust_analyzer_crate_spec")
-        .arg(format!(
-            r#"outputs(".*\.rust_analyzer_crate_spec\.json",{target_pattern})"#
-        ))
-        .arg("--output=jsonproto")
-        .output()?;
+        .args(extra_args)
+        .arg(&query)
+        .arg("--output=jsonproto");
+    let aquery_output = crate::output_with_timeout(&mut aquery_command, crate::command_timeout()?)?;
 
-    let crate_spec_files =
-        parse_aquery_output_files(execution_root, &String::from_utf8(aquery_output.stdout)?)?;
-
-    let crate_specs = crate_spec_files
-        .into_iter()
-        .map(|file| {
-            let f = File::open(&file)
-                .with_context(|| format!("Failed to open file: {}", file.display()))?;
-            serde_json::from_reader(f)
-                .with_context(|| format!("Failed to deserialize file: {}", file.display()))
-        })
-        .collect::<anyhow::Result<Vec<CrateSpec>>>()?;
+    if !aquery_output.status.success() {
+        return Err(anyhow::anyhow!(
+            "bazel aquery failed:({})\nquery: {}\n{}",
+            aquery_output.status,
+            query,
+            String::from_utf8_lossy(&aquery_output.stderr)
+        ));
+    }
 
-    consolidate_crate_specs(crate_specs)
+    Ok(String::from_utf8(aquery_output.stdout)?)
 }
 
 fn parse_aquery_output_files(
     execution_root: &Path,
     aquery_stdout: &str,
 ) -> anyhow::Result<Vec<PathBuf>> {
+    if aquery_stdout.trim().is_empty() {
+        return Err(anyhow::anyhow!("Aquery returned no output"));
+    }
+
     let out: AqueryOutput = serde_json::from_str(aquery_stdout).map_err(|_| {
         // Parsing to `AqueryOutput` failed, try parsing into a `serde_json::Value`:
         match serde_json::from_str::<serde_json::Value>(aquery_stdout) {
@@ -126,6 +575,18 @@ fn parse_aquery_output_files(
         }
     })?;
 
+    if out.actions.is_empty() {
+        if out.targets.is_empty() {
+            return Err(anyhow::anyhow!("Aquery returned an empty result, are there any Rust targets in the specified paths?."));
+        }
+        // The targets exist, but the aspect didn't generate a crate spec for any of them.
+        return Err(anyhow::anyhow!(
+            "Aquery matched {} targets (e.g. {}) but none produced a crate spec, the rust_analyzer_aspect only applies to Rust rules",
+            out.targets.len(),
+            out.targets[0].label
+        ));
+    }
+
     let artifacts = out
         .artifacts
         .iter()
@@ -137,18 +598,34 @@ fn parse_aquery_output_files(
         .map(|pf| (pf.id, pf))
         .collect::<BTreeMap<_, _>>();
 
+    // Some Bazel versions prune actions and artifacts under `--include_aspects`, so
+    // ids in the output may dangle. Skip those outputs instead of failing outright.
     let mut output_files: Vec<PathBuf> = Vec::new();
+    let mut fragment_paths: HashMap<u32, PathBuf> = HashMap::new();
     for action in out.actions {
         for output_id in action.output_ids {
-            let artifact = artifacts
-                .get(&output_id)
-                .expect("internal consistency error in bazel output");
-            let path = path_from_fragments(artifact.path_fragment_id, &path_fragments)?;
-            let path = execution_root.join(path);
-            if path.exists() {
-                output_files.push(path);
-            } else {
-                log::warn!("Skipping missing crate_spec file: {:?}", path);
+            let Some(artifact) = artifacts.get(&output_id) else {
+                log::warn!("Skipping output with unknown artifact id {output_id}");
+                continue;
+            };
+            let path = match path_from_fragments(
+                artifact.path_fragment_id,
+                &path_fragments,
+                &mut fragment_paths,
+            ) {
+                Ok(path) => path,
+                Err(err) => {
+                    log::warn!("Skipping output artifact {output_id}: {err}");
+                    continue;
+                }
+            };
+            match existing_output_file(execution_root, &path) {
+                Ok(path) => output_files.push(path),
+                Err(canonical) => log::warn!(
+                    "Skipping missing crate_spec file: {} (canonical path: {})",
+                    execution_root.join(&path).display(),
+                    canonical
+                ),
             }
         }
     }
@@ -156,64 +633,408 @@ fn parse_aquery_output_files(
     Ok(output_files)
 }
 
+/// A build event from the stream written by `bazel build --build_event_json_file`.
+/// Only the events listing output files are of interest.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildEvent {
+    named_set_of_files: Option<NamedSetOfFiles>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedSetOfFiles {
+    #[serde(default)]
+    files: Vec<BuildEventFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildEventFile {
+    name: String,
+    #[serde(default)]
+    path_prefix: Vec<String>,
+}
+
+/// Returns the crate spec files listed in a JSON build event stream, relative to the
+/// execution root, sorted and without duplicates.
+pub fn parse_build_event_crate_spec_files(build_events: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = BTreeSet::new();
+    for line in build_events.lines().filter(|line| !line.trim().is_empty()) {
+        let event: BuildEvent =
+            serde_json::from_str(line).context("Failed to parse build event")?;
+        for file in event
+            .named_set_of_files
+            .into_iter()
+            .flat_map(|set| set.files)
+        {
+            if file.name.ends_with(".rust_analyzer_crate_spec.json") {
+                let mut path: PathBuf = file.path_prefix.iter().collect();
+                path.push(&file.name);
+                files.insert(path);
+            }
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Returns the path of the output `path` under `execution_root` if it exists. The
+/// exec root is often a symlink (e.g. into a sandbox), so the path under the
+/// canonicalized exec root is tried as well. On failure, returns a description of
+/// the canonical path for diagnostics.
+fn existing_output_file(execution_root: &Path, path: &Path) -> Result<PathBuf, String> {
+    let joined = execution_root.join(path);
+    if joined.exists() {
+        return Ok(joined);
+    }
+
+    match execution_root.canonicalize() {
+        Ok(canonical_root) => {
+            let canonical = canonical_root.join(path);
+            match canonical.canonicalize() {
+                Ok(canonical) => Ok(canonical),
+                Err(_) => Err(canonical.display().to_string()),
+            }
+        }
+        Err(err) => Err(format!("unavailable, {err}")),
+    }
+}
+
+/// Returns the path of fragment `id` by walking up its parents. Output paths share
+/// long prefixes (e.g. `bazel-out/<config>/bin/...`), so the path of every fragment
+/// on the way is stored in `cache` to be reused for later lookups.
 fn path_from_fragments(
     id: u32,
     fragments: &BTreeMap<u32, &PathFragment>,
+    cache: &mut HashMap<u32, PathBuf>,
 ) -> anyhow::Result<PathBuf> {
-    let path_fragment = fragments
-        .get(&id)
-        .expect("internal consistency error in bazel output");
-
-    let buf = match path_fragment.parent_id {
-        Some(parent_id) => path_from_fragments(parent_id, fragments)?
-            .join(PathBuf::from(&path_fragment.label.clone())),
-        None => PathBuf::from(&path_fragment.label.clone()),
-    };
+    // Collect the fragments from `id` up to the first one with a known path.
+    let mut unresolved: Vec<&PathFragment> = Vec::new();
+    let mut next = Some(id);
+    let mut buf = PathBuf::new();
+    while let Some(id) = next {
+        if let Some(path) = cache.get(&id) {
+            buf = path.clone();
+            break;
+        }
+        let path_fragment = fragments
+            .get(&id)
+            .ok_or_else(|| anyhow::anyhow!("unknown path fragment id {id}"))?;
+        if unresolved.len() > fragments.len() {
+            return Err(anyhow::anyhow!("path fragment {id} is its own ancestor"));
+        }
+        unresolved.push(path_fragment);
+        next = path_fragment.parent_id;
+    }
+
+    for path_fragment in unresolved.into_iter().rev() {
+        // Some Bazel versions emit a root fragment with an empty label, which
+        // contributes nothing to the path.
+        if !path_fragment.label.is_empty() {
+            buf.push(&path_fragment.label);
+        }
+        cache.insert(path_fragment.id, buf.clone());
+    }
 
     Ok(buf)
 }
 
 /// Read all crate specs, deduplicating crates with the same ID. This happens when
-/// a rust_test depends on a rust_library, for example.
-fn consolidate_crate_specs(crate_specs: Vec<CrateSpec>) -> anyhow::Result<BTreeSet<CrateSpec>> {
-    let mut consolidated_specs: BTreeMap<String, CrateSpec> = BTreeMap::new();
+/// a rust_test depends on a rust_library, for example. Specs for the same crate
+/// built for several targets (e.g. for the host and for the target platform when
+/// cross-compiling) are kept apart, each with its target triple appended to its
+/// `crate_id`, and dependents are pointed at the variant built for their own target.
+/// `execution_root` is used to check which of several proc-macro dylibs exist.
+fn consolidate_crate_specs(
+    crate_specs: Vec<CrateSpec>,
+    options: &ConsolidationOptions,
+    execution_root: Option<&Path>,
+) -> anyhow::Result<BTreeSet<CrateSpec>> {
+    check_conflicts(&crate_specs, options)?;
+
+    let mut consolidated_specs: BTreeMap<(String, String), CrateSpec> = BTreeMap::new();
+    // Maps the crate_id of each merged spec to the crate_id of the spec it was
+    // merged into, so dependencies on it can be redirected.
+    let mut merged_crate_ids: BTreeMap<String, String> = BTreeMap::new();
     for mut spec in crate_specs.into_iter() {
         log::debug!("{:?}", spec);
-        if let Some(existing) = consolidated_specs.get_mut(&spec.crate_id) {
+        normalize_cfgs(&mut spec.cfg);
+        let key = (options.key.of(&spec), spec.target.clone());
+        if let Some(existing) = consolidated_specs.get_mut(&key) {
+            if spec.crate_id != existing.crate_id {
+                merged_crate_ids.insert(spec.crate_id.clone(), existing.crate_id.clone());
+                existing.aliases.extend(spec.aliases);
+            }
+
             existing.deps.extend(spec.deps);
 
             spec.cfg.retain(|cfg| !existing.cfg.contains(cfg));
             existing.cfg.extend(spec.cfg);
 
+            spec.rustc_flags
+                .retain(|flag| !existing.rustc_flags.contains(flag));
+            existing.rustc_flags.extend(spec.rustc_flags);
+
+            // A test may see more of the source tree (e.g. test data) than its
+            // library, so keep every directory either spec covers.
+            match (&mut existing.source, spec.source) {
+                (Some(existing_source), Some(source)) => {
+                    for (dirs, new_dirs) in [
+                        (&mut existing_source.include_dirs, source.include_dirs),
+                        (&mut existing_source.exclude_dirs, source.exclude_dirs),
+                    ] {
+                        for dir in new_dirs {
+                            if !dirs.contains(&dir) {
+                                dirs.push(dir);
+                            }
+                        }
+                    }
+                }
+                (existing_source @ None, source) => *existing_source = source,
+                (Some(_), None) => {}
+            }
+
+            // Like display_name below, the library's env wins on conflicts.
+            if spec.crate_type == "rlib" {
+                existing.env.extend(spec.env);
+            } else {
+                for (key, value) in spec.env {
+                    existing.env.entry(key).or_insert(value);
+                }
+            }
+
             // display_name should match the library's crate name because Rust Analyzer
             // seems to use display_name for matching crate entries in rust-project.json
             // against symbols in source files. For more details, see
             // https://github.com/bazelbuild/rules_rust/issues/1032
             if spec.crate_type == "rlib" {
                 existing.display_name = spec.display_name;
+                if !spec.bazel_target.is_empty() {
+                    existing.bazel_target = spec.bazel_target;
+                }
                 existing.crate_type = "rlib".into();
             }
 
             // For proc-macro crates that exist within the workspace, there will be a
             // generated crate-spec in both the fastbuild and opt-exec configuration.
-            // Prefer proc macro paths with an opt-exec component in the path.
-            if let Some(dylib_path) = spec.proc_macro_dylib_path.as_ref() {
-                const OPT_PATH_COMPONENT: &str = "-opt-exec-";
-                if dylib_path.contains(OPT_PATH_COMPONENT) {
-                    existing.proc_macro_dylib_path.replace(dylib_path.clone());
+            // When cross-compiling there may also be several exec configurations.
+            if let Some(dylib_path) = spec.proc_macro_dylib_path {
+                if prefer_proc_macro_dylib(
+                    &dylib_path,
+                    existing.proc_macro_dylib_path.as_deref(),
+                    host_cpu(),
+                    |path| dylib_modified(execution_root, path),
+                ) {
+                    existing.proc_macro_dylib_path.replace(dylib_path);
                 }
             }
         } else {
-            consolidated_specs.insert(spec.crate_id.clone(), spec);
+            consolidated_specs.insert(key, spec);
         }
     }
 
+    if !merged_crate_ids.is_empty() {
+        let redirect =
+            |crate_id: String| merged_crate_ids.get(&crate_id).cloned().unwrap_or(crate_id);
+        for spec in consolidated_specs.values_mut() {
+            spec.deps = std::mem::take(&mut spec.deps)
+                .into_iter()
+                .map(redirect)
+                .filter(|dep| *dep != spec.crate_id)
+                .collect();
+            spec.aliases = std::mem::take(&mut spec.aliases)
+                .into_iter()
+                .map(|(crate_id, alias)| (redirect(crate_id), alias))
+                .collect();
+        }
+    }
+
+    split_by_target(&mut consolidated_specs);
+
     Ok(consolidated_specs.into_values().collect())
 }
 
+/// Gives crates consolidated for more than one target distinct `crate_id`s by
+/// appending the target triple, and redirects dependencies on them to the variant
+/// matching the dependent's target, or the first variant if there is none.
+fn split_by_target(consolidated_specs: &mut BTreeMap<(String, String), CrateSpec>) {
+    let mut variants: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    for spec in consolidated_specs.values() {
+        variants.entry(spec.crate_id.clone()).or_default().insert(
+            spec.target.clone(),
+            format!("{}-{}", spec.crate_id, spec.target),
+        );
+    }
+    variants.retain(|_, targets| targets.len() > 1);
+    if variants.is_empty() {
+        return;
+    }
+
+    for spec in consolidated_specs.values_mut() {
+        let target = spec.target.clone();
+        let resolve = |crate_id: String| match variants.get(&crate_id) {
+            Some(targets) => targets
+                .get(&target)
+                .or_else(|| targets.values().next())
+                .cloned()
+                .unwrap_or(crate_id),
+            None => crate_id,
+        };
+        spec.deps = std::mem::take(&mut spec.deps)
+            .into_iter()
+            .map(resolve)
+            .collect();
+        spec.aliases = std::mem::take(&mut spec.aliases)
+            .into_iter()
+            .map(|(crate_id, alias)| (resolve(crate_id), alias))
+            .collect();
+        if let Some(targets) = variants.get(&spec.crate_id) {
+            log::debug!(
+                "Splitting {} built for {} targets",
+                spec.crate_id,
+                targets.len()
+            );
+            spec.crate_id = targets[&spec.target].clone();
+        }
+    }
+}
+
+/// Returns whether the proc-macro dylib at `candidate` should replace `current`.
+/// rust-analyzer can only load dylibs built for the machine it runs on, so paths
+/// in an output directory for the `host_cpu` are preferred, followed by paths
+/// with an opt-exec component, which are always built when the macro is used.
+/// If neither decides, the dylib that exists and was `modified` most recently wins,
+/// as exec configuration names vary between platforms and compilation modes.
+fn prefer_proc_macro_dylib(
+    candidate: &str,
+    current: Option<&str>,
+    host_cpu: Option<&str>,
+    modified: impl Fn(&str) -> Option<SystemTime>,
+) -> bool {
+    const OPT_PATH_COMPONENT: &str = "-opt-exec-";
+    let preference = |path: &str| {
+        let built_for_host = host_cpu.is_some_and(|cpu| {
+            path.split('/').any(|component| {
+                component
+                    .strip_prefix(cpu)
+                    .is_some_and(|rest| rest.starts_with('-'))
+            })
+        });
+        (
+            built_for_host,
+            path.contains(OPT_PATH_COMPONENT),
+            modified(path),
+        )
+    };
+
+    match current {
+        None => true,
+        Some(current) => {
+            let (candidate, current) = (preference(candidate), preference(current));
+            // Among equally preferred opt-exec paths the last one wins.
+            candidate > current || (candidate == current && candidate.1)
+        }
+    }
+}
+
+/// Returns the modification time of the proc-macro dylib at `path`, or `None` if
+/// it doesn't exist or can't be located.
+fn dylib_modified(execution_root: Option<&Path>, path: &str) -> Option<SystemTime> {
+    let path = match path.strip_prefix("__EXEC_ROOT__/") {
+        Some(relative) => execution_root?.join(relative),
+        None => execution_root.map_or_else(|| PathBuf::from(path), |root| root.join(path)),
+    };
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Returns the Bazel `--cpu` name of the machine gen_rust_project runs on, which
+/// prefixes its output directories (e.g. `bazel-out/k8-opt-exec-.../`).
+fn host_cpu() -> Option<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => Some("k8"),
+        ("linux", "aarch64") => Some("aarch64"),
+        ("macos", "x86_64") => Some("darwin_x86_64"),
+        ("macos", "aarch64") => Some("darwin_arm64"),
+        ("windows", "x86_64") => Some("x64_windows"),
+        ("windows", "aarch64") => Some("arm64_windows"),
+        ("freebsd", "x86_64") => Some("freebsd"),
+        _ => None,
+    }
+}
+
+/// Checks that specs sharing a consolidation key and target agree on `edition` and
+/// `root_module`. Differing values are the result of duplicate definitions (or a
+/// `crate_id` collision) rather than a library and its test, and merging them would
+/// produce a crate that matches neither.
+fn check_conflicts(
+    crate_specs: &[CrateSpec],
+    options: &ConsolidationOptions,
+) -> anyhow::Result<()> {
+    let mut editions: BTreeMap<(String, &str), BTreeSet<&str>> = BTreeMap::new();
+    let mut root_modules: BTreeMap<(String, &str), BTreeSet<&str>> = BTreeMap::new();
+    for spec in crate_specs {
+        editions
+            .entry((options.key.of(spec), &spec.target))
+            .or_default()
+            .insert(&spec.edition);
+        root_modules
+            .entry((options.key.of(spec), &spec.target))
+            .or_default()
+            .insert(&spec.root_module);
+    }
+
+    let mut conflicts = Vec::new();
+    for ((key, _), editions) in editions {
+        if editions.len() > 1 {
+            conflicts.push(format!("{key}: conflicting editions {editions:?}"));
+        }
+    }
+    for ((key, _), root_modules) in root_modules {
+        if root_modules.len() > 1 {
+            conflicts.push(format!("{key}: conflicting root modules {root_modules:?}"));
+        }
+    }
+
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    if options.strict {
+        return Err(anyhow::anyhow!(
+            "Crate specs with the same {} have incompatible metadata:\n  {}",
+            options.key,
+            conflicts.join("\n  ")
+        ));
+    }
+
+    for conflict in conflicts {
+        log::warn!(
+            "Merging crate specs with incompatible metadata: {}",
+            conflict
+        );
+    }
+
+    Ok(())
+}
+
+/// Rewrites each cfg into a canonical `key` or `key="value"` form and removes
+/// duplicates, so whitespace differences such as `target_os = "linux"` and
+/// `target_os="linux"` don't produce distinct entries. Values are kept verbatim
+/// since they are case-sensitive.
+fn normalize_cfgs(cfgs: &mut Vec<String>) {
+    let mut seen = BTreeSet::new();
+    cfgs.retain_mut(|cfg| {
+        *cfg = match cfg.split_once('=') {
+            Some((key, value)) => format!("{}={}", key.trim(), value.trim()),
+            None => cfg.trim().to_owned(),
+        };
+        seen.insert(cfg.clone())
+    });
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::TestDir;
     use itertools::Itertools;
 
     #[test]
@@ -223,6 +1044,7 @@ mod test {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib".into(),
+                bazel_target: "//:mylib".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -233,11 +1055,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-extra_test_dep.rs".into(),
                 display_name: "extra_test_dep".into(),
+                bazel_target: "//:extra_test_dep".into(),
                 edition: "2018".into(),
                 root_module: "extra_test_dep.rs".into(),
                 is_workspace_member: true,
@@ -248,11 +1072,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-lib_dep.rs".into(),
                 display_name: "lib_dep".into(),
+                bazel_target: "//:lib_dep".into(),
                 edition: "2018".into(),
                 root_module: "lib_dep.rs".into(),
                 is_workspace_member: true,
@@ -263,11 +1089,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib_test".into(),
+                bazel_target: "//:mylib_test".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -278,16 +1106,18 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "bin".into(),
+                rustc_flags: vec![],
             },
         ];
 
         assert_eq!(
-            consolidate_crate_specs(crate_specs).unwrap(),
+            consolidate_crate_specs(crate_specs, &ConsolidationOptions::default(), None).unwrap(),
             BTreeSet::from([
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-mylib.rs".into(),
                     display_name: "mylib".into(),
+                    bazel_target: "//:mylib".into(),
                     edition: "2018".into(),
                     root_module: "mylib.rs".into(),
                     is_workspace_member: true,
@@ -298,11 +1128,13 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-extra_test_dep.rs".into(),
                     display_name: "extra_test_dep".into(),
+                    bazel_target: "//:extra_test_dep".into(),
                     edition: "2018".into(),
                     root_module: "extra_test_dep.rs".into(),
                     is_workspace_member: true,
@@ -313,11 +1145,13 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-lib_dep.rs".into(),
                     display_name: "lib_dep".into(),
+                    bazel_target: "//:lib_dep".into(),
                     edition: "2018".into(),
                     root_module: "lib_dep.rs".into(),
                     is_workspace_member: true,
@@ -328,6 +1162,7 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
             ])
         );
@@ -340,6 +1175,7 @@ mod test {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib_test".into(),
+                bazel_target: "//:mylib_test".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -350,11 +1186,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "bin".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib".into(),
+                bazel_target: "//:mylib".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -365,11 +1203,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-extra_test_dep.rs".into(),
                 display_name: "extra_test_dep".into(),
+                bazel_target: "//:extra_test_dep".into(),
                 edition: "2018".into(),
                 root_module: "extra_test_dep.rs".into(),
                 is_workspace_member: true,
@@ -380,11 +1220,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-lib_dep.rs".into(),
                 display_name: "lib_dep".into(),
+                bazel_target: "//:lib_dep".into(),
                 edition: "2018".into(),
                 root_module: "lib_dep.rs".into(),
                 is_workspace_member: true,
@@ -395,16 +1237,18 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
         ];
 
         assert_eq!(
-            consolidate_crate_specs(crate_specs).unwrap(),
+            consolidate_crate_specs(crate_specs, &ConsolidationOptions::default(), None).unwrap(),
             BTreeSet::from([
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-mylib.rs".into(),
                     display_name: "mylib".into(),
+                    bazel_target: "//:mylib".into(),
                     edition: "2018".into(),
                     root_module: "mylib.rs".into(),
                     is_workspace_member: true,
@@ -415,11 +1259,13 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-extra_test_dep.rs".into(),
                     display_name: "extra_test_dep".into(),
+                    bazel_target: "//:extra_test_dep".into(),
                     edition: "2018".into(),
                     root_module: "extra_test_dep.rs".into(),
                     is_workspace_member: true,
@@ -430,11 +1276,13 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-lib_dep.rs".into(),
                     display_name: "lib_dep".into(),
+                    bazel_target: "//:lib_dep".into(),
                     edition: "2018".into(),
                     root_module: "lib_dep.rs".into(),
                     is_workspace_member: true,
@@ -445,6 +1293,7 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
             ])
         );
@@ -462,6 +1311,7 @@ mod test {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib".into(),
+                bazel_target: "//:mylib".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -472,11 +1322,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib_test".into(),
+                bazel_target: "//:mylib_test".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -487,11 +1339,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "bin".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib.rs".into(),
                 display_name: "mylib_main".into(),
+                bazel_target: "//:mylib_main".into(),
                 edition: "2018".into(),
                 root_module: "mylib.rs".into(),
                 is_workspace_member: true,
@@ -502,11 +1356,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "bin".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-mylib2.rs".into(),
                 display_name: "mylib2".into(),
+                bazel_target: "//:mylib2".into(),
                 edition: "2018".into(),
                 root_module: "mylib2.rs".into(),
                 is_workspace_member: true,
@@ -517,17 +1373,19 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             },
         ];
 
         for perm in crate_specs.into_iter().permutations(4) {
             assert_eq!(
-                consolidate_crate_specs(perm).unwrap(),
+                consolidate_crate_specs(perm, &ConsolidationOptions::default(), None).unwrap(),
                 BTreeSet::from([
                     CrateSpec {
                         aliases: BTreeMap::new(),
                         crate_id: "ID-mylib.rs".into(),
                         display_name: "mylib".into(),
+                        bazel_target: "//:mylib".into(),
                         edition: "2018".into(),
                         root_module: "mylib.rs".into(),
                         is_workspace_member: true,
@@ -538,11 +1396,13 @@ mod test {
                         env: BTreeMap::new(),
                         target: "x86_64-unknown-linux-gnu".into(),
                         crate_type: "rlib".into(),
+                        rustc_flags: vec![],
                     },
                     CrateSpec {
                         aliases: BTreeMap::new(),
                         crate_id: "ID-mylib2.rs".into(),
                         display_name: "mylib2".into(),
+                        bazel_target: "//:mylib2".into(),
                         edition: "2018".into(),
                         root_module: "mylib2.rs".into(),
                         is_workspace_member: true,
@@ -553,12 +1413,255 @@ mod test {
                         env: BTreeMap::new(),
                         target: "x86_64-unknown-linux-gnu".into(),
                         crate_type: "rlib".into(),
+                        rustc_flags: vec![],
                     },
                 ])
             );
         }
     }
 
+    #[test]
+    fn get_crate_specs_requires_targets() {
+        let err = get_crate_specs(
+            Path::new("/nonexistent/bazel"),
+            Path::new("/nonexistent/workspace"),
+            Path::new("/nonexistent/execution_root"),
+            &[],
+            "@rules_rust//rust:defs.bzl%rust_analyzer_aspect",
+            None,
+            &[],
+            false,
+            &ConsolidationOptions::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "no targets provided to generate crate info"
+        );
+    }
+
+    #[test]
+    fn crate_spec_query_dedupes_targets() {
+        let query = crate_spec_query(&["//a".into(), "//b".into(), "//a".into()], false);
+
+        assert_eq!(
+            query,
+            r#"outputs(".*\.rust_analyzer_crate_spec\.json",deps(//a)+deps(//b))"#
+        );
+        assert_eq!(query.matches("deps(//a)").count(), 1);
+        assert_eq!(query.matches("deps(//b)").count(), 1);
+
+        assert_eq!(
+            crate_spec_query(&["//b".into(), "//a".into()], true),
+            r#"outputs(".*\.rust_analyzer_crate_spec\.json",//a+//b)"#
+        );
+    }
+
+    #[test]
+    fn consolidate_keeps_targets_apart() {
+        let spec = |crate_id: &str, target: &str, deps: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: crate_id.into(),
+            display_name: crate_id
+                .trim_start_matches("ID-")
+                .trim_end_matches(".rs")
+                .into(),
+            bazel_target: "//:mylib".into(),
+            edition: "2018".into(),
+            root_module: crate_id.trim_start_matches("ID-").into(),
+            is_workspace_member: true,
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: target.into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let host = "x86_64-unknown-linux-gnu";
+        let device = "thumbv7em-none-eabihf";
+        let crate_specs = vec![
+            spec("ID-mylib.rs", host, &[]),
+            spec("ID-mylib.rs", device, &[]),
+            spec("ID-firmware.rs", device, &["ID-mylib.rs"]),
+            spec("ID-tool.rs", host, &["ID-mylib.rs"]),
+        ];
+
+        let consolidated =
+            consolidate_crate_specs(crate_specs, &ConsolidationOptions::default(), None).unwrap();
+        assert_eq!(
+            consolidated
+                .iter()
+                .map(|c| (
+                    c.crate_id.as_str(),
+                    c.target.as_str(),
+                    c.deps.iter().map(String::as_str).collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "ID-firmware.rs",
+                    device,
+                    vec!["ID-mylib.rs-thumbv7em-none-eabihf"]
+                ),
+                ("ID-mylib.rs-thumbv7em-none-eabihf", device, vec![]),
+                ("ID-mylib.rs-x86_64-unknown-linux-gnu", host, vec![]),
+                (
+                    "ID-tool.rs",
+                    host,
+                    vec!["ID-mylib.rs-x86_64-unknown-linux-gnu"]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_crate_spec_without_bazel_target() {
+        // Specs from older aspects have no bazel_target.
+        let mut spec: serde_json::Value =
+            serde_json::from_str(&crate_spec_json("ID-mylib.rs", "mylib", "rlib")).unwrap();
+        spec.as_object_mut().unwrap().remove("bazel_target");
+        let spec: CrateSpec = serde_json::from_value(spec).unwrap();
+        assert_eq!(spec.bazel_target, "");
+    }
+
+    #[test]
+    fn consolidate_unions_rustc_flags() {
+        // Specs from older aspects have no rustc_flags.
+        let lib: CrateSpec =
+            serde_json::from_str(&crate_spec_json("ID-mylib.rs", "mylib", "rlib")).unwrap();
+        assert!(lib.rustc_flags.is_empty());
+
+        let mut lib_test: serde_json::Value =
+            serde_json::from_str(&crate_spec_json("ID-mylib.rs", "mylib_test", "bin")).unwrap();
+        lib_test["rustc_flags"] = serde_json::json!(["-Zcrate-attr=feature(test)", "-Dwarnings"]);
+        let lib_test: CrateSpec = serde_json::from_value(lib_test).unwrap();
+        assert_eq!(
+            lib_test.rustc_flags,
+            ["-Zcrate-attr=feature(test)", "-Dwarnings"]
+        );
+
+        let mut other_test = lib_test.clone();
+        other_test.rustc_flags = vec!["-Dwarnings".into(), "-Zshare-generics".into()];
+
+        let consolidated = consolidate_crate_specs(
+            vec![lib, lib_test, other_test],
+            &ConsolidationOptions::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(consolidated.len(), 1);
+        assert_eq!(
+            consolidated.first().unwrap().rustc_flags,
+            [
+                "-Zcrate-attr=feature(test)",
+                "-Dwarnings",
+                "-Zshare-generics"
+            ]
+        );
+    }
+
+    #[test]
+    fn consolidate_unions_source_dirs() {
+        let spec =
+            |display_name: &str, crate_type: &str, source: Option<CrateSpecSource>| CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-mylib.rs".into(),
+                display_name: display_name.into(),
+                bazel_target: format!("//:{display_name}"),
+                edition: "2018".into(),
+                root_module: "mylib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source,
+                cfg: vec!["test".into()],
+                env: BTreeMap::new(),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: crate_type.into(),
+                rustc_flags: vec![],
+            };
+        let source = |include_dirs: &[&str], exclude_dirs: &[&str]| CrateSpecSource {
+            include_dirs: include_dirs.iter().map(|d| d.to_string()).collect(),
+            exclude_dirs: exclude_dirs.iter().map(|d| d.to_string()).collect(),
+        };
+
+        let consolidated = consolidate_crate_specs(
+            vec![
+                spec("mylib", "rlib", None),
+                spec("mylib_test", "bin", Some(source(&["src"], &["src/vendor"]))),
+                spec(
+                    "mylib_other_test",
+                    "bin",
+                    Some(source(&["src", "testdata"], &["testdata/large"])),
+                ),
+            ],
+            &ConsolidationOptions::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(consolidated.len(), 1);
+        assert_eq!(
+            consolidated.first().unwrap().source,
+            Some(source(
+                &["src", "testdata"],
+                &["src/vendor", "testdata/large"]
+            ))
+        );
+    }
+
+    #[test]
+    fn consolidate_merges_env() {
+        let spec = |display_name: &str, crate_type: &str, env: &[(&str, &str)]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: "ID-mylib.rs".into(),
+            display_name: display_name.into(),
+            bazel_target: format!("//:{display_name}"),
+            edition: "2018".into(),
+            root_module: "mylib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: crate_type.into(),
+            rustc_flags: vec![],
+        };
+        let crate_specs = vec![
+            spec(
+                "mylib",
+                "rlib",
+                &[("CARGO_PKG_NAME", "mylib"), ("SHARED", "lib")],
+            ),
+            spec(
+                "mylib_test",
+                "bin",
+                &[("TEST_DATA", "testdata"), ("SHARED", "test")],
+            ),
+        ];
+
+        for perm in crate_specs.into_iter().permutations(2) {
+            let consolidated =
+                consolidate_crate_specs(perm, &ConsolidationOptions::default(), None).unwrap();
+            assert_eq!(consolidated.len(), 1);
+            assert_eq!(
+                consolidated.first().unwrap().env,
+                BTreeMap::from([
+                    ("CARGO_PKG_NAME".to_owned(), "mylib".to_owned()),
+                    ("SHARED".to_owned(), "lib".to_owned()),
+                    ("TEST_DATA".to_owned(), "testdata".to_owned()),
+                ])
+            );
+        }
+    }
+
     #[test]
     fn consolidate_proc_macro_prefer_exec() {
         // proc macro crates should prefer the -opt-exec- path which is always generated
@@ -569,6 +1672,7 @@ mod test {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-myproc_macro.rs".into(),
                 display_name: "myproc_macro".into(),
+                bazel_target: "//:myproc_macro".into(),
                 edition: "2018".into(),
                 root_module: "myproc_macro.rs".into(),
                 is_workspace_member: true,
@@ -582,11 +1686,13 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "proc_macro".into(),
+                rustc_flags: vec![],
             },
             CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-myproc_macro.rs".into(),
                 display_name: "myproc_macro".into(),
+                bazel_target: "//:myproc_macro".into(),
                 edition: "2018".into(),
                 root_module: "myproc_macro.rs".into(),
                 is_workspace_member: true,
@@ -599,16 +1705,18 @@ mod test {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "proc_macro".into(),
+                rustc_flags: vec![],
             },
         ];
 
         for perm in crate_specs.into_iter().permutations(2) {
             assert_eq!(
-                consolidate_crate_specs(perm).unwrap(),
+                consolidate_crate_specs(perm, &ConsolidationOptions::default(), None).unwrap(),
                 BTreeSet::from([CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-myproc_macro.rs".into(),
                     display_name: "myproc_macro".into(),
+                    bazel_target: "//:myproc_macro".into(),
                     edition: "2018".into(),
                     root_module: "myproc_macro.rs".into(),
                     is_workspace_member: true,
@@ -622,8 +1730,748 @@ mod test {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "proc_macro".into(),
+                    rustc_flags: vec![],
                 },])
             );
         }
     }
+
+    #[test]
+    fn prefer_proc_macro_dylib_for_host() {
+        let host = "bazel-out/k8-opt-exec-F005BA11/bin/myproc_macro/libmyproc_macro-12345.so";
+        let non_host =
+            "bazel-out/darwin_arm64-opt-exec-F005BA11/bin/myproc_macro/libmyproc_macro-12345.so";
+        let host_fastbuild = "bazel-out/k8-fastbuild/bin/myproc_macro/libmyproc_macro-12345.so";
+        let prefer = |candidate, current, host_cpu| {
+            prefer_proc_macro_dylib(candidate, Some(current), host_cpu, |_| None)
+        };
+
+        // The host's exec configuration wins regardless of order.
+        assert!(prefer(host, non_host, Some("k8")));
+        assert!(!prefer(non_host, host, Some("k8")));
+
+        // A host build in another configuration still beats a non-host one.
+        assert!(prefer(host_fastbuild, non_host, Some("k8")));
+
+        // Without a known host, fall back to preferring opt-exec paths.
+        assert!(prefer(non_host, host_fastbuild, None));
+        assert!(!prefer(host_fastbuild, non_host, None));
+
+        // Directories merely starting with the host cpu name don't match it.
+        assert!(!prefer(
+            "bazel-out/k8s-fastbuild/bin/libmyproc_macro-12345.so",
+            non_host,
+            Some("k8")
+        ));
+    }
+
+    #[test]
+    fn prefer_proc_macro_dylib_by_mtime() {
+        let execution_root = TestDir::new("proc_macro_mtime");
+        let old = "bazel-out/exec-ST-1/bin/libmyproc_macro-12345.so";
+        let new = "bazel-out/exec-ST-2/bin/libmyproc_macro-12345.so";
+        let missing = "bazel-out/exec-ST-3/bin/libmyproc_macro-12345.so";
+        for (path, age) in [(old, 60), (new, 0)] {
+            let path = execution_root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let file = std::fs::File::create(&path).unwrap();
+            file.set_modified(SystemTime::now() - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+
+        let prefer = |candidate: &str, current: &str| {
+            prefer_proc_macro_dylib(
+                &format!("__EXEC_ROOT__/{candidate}"),
+                Some(&format!("__EXEC_ROOT__/{current}")),
+                Some("k8"),
+                |path| dylib_modified(Some(&execution_root), path),
+            )
+        };
+
+        // The most recently modified dylib wins, regardless of order.
+        assert!(prefer(new, old));
+        assert!(!prefer(old, new));
+        // Existing dylibs beat missing ones.
+        assert!(prefer(old, missing));
+        assert!(!prefer(missing, old));
+
+        // An opt-exec path is still preferred over a newer dylib.
+        assert!(prefer_proc_macro_dylib(
+            "__EXEC_ROOT__/bazel-out/arm64-opt-exec-F005BA11/bin/libmyproc_macro-12345.so",
+            Some(&format!("__EXEC_ROOT__/{new}")),
+            Some("k8"),
+            |path| dylib_modified(Some(&execution_root), path),
+        ));
+    }
+
+    #[test]
+    fn consolidate_normalizes_cfg_whitespace() {
+        let crate_specs = vec![
+            CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-mylib.rs".into(),
+                display_name: "mylib".into(),
+                bazel_target: "//:mylib".into(),
+                edition: "2018".into(),
+                root_module: "mylib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![
+                    "target_os = \"linux\"".into(),
+                    " test ".into(),
+                    "feature=\"Foo\"".into(),
+                ],
+                env: BTreeMap::new(),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            },
+            CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-mylib.rs".into(),
+                display_name: "mylib_test".into(),
+                bazel_target: "//:mylib_test".into(),
+                edition: "2018".into(),
+                root_module: "mylib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![
+                    "target_os=\"linux\"".into(),
+                    "target_os =\"linux\"".into(),
+                    "test".into(),
+                    "feature = \"foo\"".into(),
+                ],
+                env: BTreeMap::new(),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "bin".into(),
+                rustc_flags: vec![],
+            },
+        ];
+
+        let specs =
+            consolidate_crate_specs(crate_specs, &ConsolidationOptions::default(), None).unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(
+            specs.into_iter().next().unwrap().cfg,
+            vec![
+                "target_os=\"linux\"".to_owned(),
+                "test".to_owned(),
+                "feature=\"Foo\"".to_owned(),
+                "feature=\"foo\"".to_owned(),
+            ]
+        );
+    }
+
+    /// Renders aquery output for a single action producing `specs/{file_name}`.
+    fn aquery_output_for(file_name: &str) -> String {
+        format!(
+            r#"{{
+                "artifacts": [{{"id": 1, "pathFragmentId": 2}}],
+                "actions": [{{"outputIds": [1]}}],
+                "pathFragments": [
+                    {{"id": 1, "label": "specs"}},
+                    {{"id": 2, "label": "{file_name}", "parentId": 1}}
+                ]
+            }}"#
+        )
+    }
+
+    fn crate_spec_json(crate_id: &str, display_name: &str, crate_type: &str) -> String {
+        format!(
+            r#"{{
+                "aliases": {{}},
+                "crate_id": "{crate_id}",
+                "display_name": "{display_name}",
+                "bazel_target": "//:{display_name}",
+                "edition": "2018",
+                "root_module": "mylib.rs",
+                "is_workspace_member": true,
+                "deps": [],
+                "proc_macro_dylib_path": null,
+                "source": null,
+                "cfg": ["test"],
+                "env": {{}},
+                "target": "x86_64-unknown-linux-gnu",
+                "crate_type": "{crate_type}"
+            }}"#
+        )
+    }
+
+    #[test]
+    fn read_crate_specs_preserves_order() {
+        let dir = TestDir::new("read_crate_specs");
+        let files: Vec<PathBuf> = (0..500)
+            .map(|i| {
+                let file = dir.join(format!("{i}.rust_analyzer_crate_spec.json"));
+                std::fs::write(
+                    &file,
+                    crate_spec_json(&format!("ID-{i}.rs"), &format!("lib{i}"), "rlib"),
+                )
+                .unwrap();
+                file
+            })
+            .collect();
+
+        let crate_specs = read_crate_specs(&files).unwrap();
+        assert_eq!(
+            crate_specs
+                .iter()
+                .map(|c| c.crate_id.clone())
+                .collect::<Vec<_>>(),
+            (0..500).map(|i| format!("ID-{i}.rs")).collect::<Vec<_>>()
+        );
+
+        // Errors still name the offending file.
+        std::fs::write(&files[250], "not json").unwrap();
+        let err = read_crate_specs(&files).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Failed to deserialize file: {}", files[250].display())
+        );
+    }
+
+    #[test]
+    fn read_crate_specs_names_unexpected_field() {
+        let dir = TestDir::new("read_crate_specs_unexpected_field");
+        let file = dir.join("mylib.rust_analyzer_crate_spec.json");
+        let mut spec: serde_json::Value =
+            serde_json::from_str(&crate_spec_json("ID-mylib.rs", "mylib", "rlib")).unwrap();
+        spec["build_script_outputs"] = serde_json::json!([]);
+        std::fs::write(&file, spec.to_string()).unwrap();
+
+        let err = read_crate_specs(std::slice::from_ref(&file)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "crate spec //:mylib (file {}) has unexpected field `build_script_outputs`, your aspect and tool versions may be mismatched",
+                file.display()
+            )
+        );
+    }
+
+    #[test]
+    fn parse_build_event_crate_spec_files_from_stream() {
+        let build_events = [
+            r#"{"id":{"started":{}},"started":{"uuid":"0a1b2c","command":"build"}}"#,
+            r#"{"id":{"namedSet":{"id":"0"}},"namedSetOfFiles":{"files":[{"name":"foo/foo.rust_analyzer_crate_spec.json","uri":"file:///execroot/_main/bazel-out/k8-fastbuild/bin/foo/foo.rust_analyzer_crate_spec.json","pathPrefix":["bazel-out","k8-fastbuild","bin"]},{"name":"foo/generated.rs","pathPrefix":["bazel-out","k8-fastbuild","bin"]}]}}"#,
+            r#"{"id":{"namedSet":{"id":"1"}},"namedSetOfFiles":{"files":[{"name":"bar/bar.rust_analyzer_crate_spec.json","pathPrefix":["bazel-out","k8-fastbuild","bin"]}],"fileSets":[{"id":"0"}]}}"#,
+            r#"{"id":{"namedSet":{"id":"2"}},"namedSetOfFiles":{"files":[{"name":"foo/foo.rust_analyzer_crate_spec.json","pathPrefix":["bazel-out","k8-fastbuild","bin"]}]}}"#,
+            "",
+            r#"{"id":{"buildFinished":{}},"finished":{"exitCode":{"name":"SUCCESS"}}}"#,
+        ]
+        .join("\n");
+
+        assert_eq!(
+            parse_build_event_crate_spec_files(&build_events).unwrap(),
+            [
+                PathBuf::from("bazel-out/k8-fastbuild/bin/bar/bar.rust_analyzer_crate_spec.json"),
+                PathBuf::from("bazel-out/k8-fastbuild/bin/foo/foo.rust_analyzer_crate_spec.json"),
+            ]
+        );
+        assert!(parse_build_event_crate_spec_files("not json").is_err());
+    }
+
+    #[test]
+    fn get_crate_specs_from_dir_reads_nested_files() {
+        let dir = TestDir::new("crate_specs_from_dir");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(
+            dir.join("mylib.rust_analyzer_crate_spec.json"),
+            crate_spec_json("ID-mylib.rs", "mylib", "rlib"),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("nested/other.rust_analyzer_crate_spec.json"),
+            crate_spec_json("ID-other.rs", "other", "rlib"),
+        )
+        .unwrap();
+        std::fs::write(dir.join("nested/unrelated.json"), "not a crate spec").unwrap();
+
+        let crate_specs =
+            get_crate_specs_from_dir(&dir, &dir, &ConsolidationOptions::default()).unwrap();
+        assert_eq!(
+            crate_specs
+                .iter()
+                .map(|c| (c.crate_id.as_str(), c.bazel_target.as_str()))
+                .collect::<Vec<_>>(),
+            [("ID-mylib.rs", "//:mylib"), ("ID-other.rs", "//:other")]
+        );
+    }
+
+    #[test]
+    fn cached_crate_specs_hits_and_misses() {
+        let workspace = TestDir::new("crate_specs_cache");
+        std::fs::write(workspace.join("BUILD.bazel"), "").unwrap();
+        let cache_dir = workspace.join("cache");
+
+        let crate_specs = || -> anyhow::Result<BTreeSet<CrateSpec>> {
+            Ok(BTreeSet::from([serde_json::from_str(&crate_spec_json(
+                "ID-mylib.rs",
+                "mylib",
+                "rlib",
+            ))?]))
+        };
+        let computed = std::cell::Cell::new(0);
+        let get = |targets: &[String], refresh: bool| {
+            let key = crate_specs_cache_key(&workspace, targets, "");
+            cached_crate_specs(&cache_dir, key, refresh, || {
+                computed.set(computed.get() + 1);
+                crate_specs()
+            })
+            .unwrap()
+        };
+
+        let targets = vec!["//a/...".to_owned(), "//b/...".to_owned()];
+        assert_eq!(get(&targets, false), crate_specs().unwrap());
+        // Identical inputs, in any order, read from the cache.
+        let reordered = vec!["//b/...".to_owned(), "//a/...".to_owned()];
+        assert_eq!(get(&reordered, false), crate_specs().unwrap());
+        // A different target set misses.
+        get(&["//a/...".to_owned()], false);
+        // Refreshing ignores the cache.
+        get(&targets, true);
+        assert_eq!(computed.get(), 3);
+
+        // Changing a build input replaces the entry for the same targets.
+        let entries = || std::fs::read_dir(&cache_dir).unwrap().count();
+        assert_eq!(entries(), 2);
+        std::fs::write(workspace.join("deps.bzl"), "").unwrap();
+        get(&targets, false);
+        get(&targets, false);
+        assert_eq!(computed.get(), 4);
+        assert_eq!(entries(), 2);
+    }
+
+    #[test]
+    fn crate_specs_cache_key_inputs() {
+        let workspace = TestDir::new("crate_specs_cache_key");
+        std::fs::create_dir_all(workspace.join("pkg")).unwrap();
+        std::fs::create_dir_all(workspace.join("node_modules/dep")).unwrap();
+        std::fs::write(workspace.join("pkg/BUILD.bazel"), "").unwrap();
+        let key = || crate_specs_cache_key(&workspace, &["//...".to_owned()], "");
+        let set_mtime = |path: &str, secs: u64| {
+            std::fs::File::options()
+                .append(true)
+                .create(true)
+                .open(workspace.join(path))
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        let initial = key();
+        assert_eq!(initial, key());
+
+        // Lock files and .bzl files are build inputs.
+        set_mtime("Cargo.lock", 1);
+        let with_lock = key();
+        assert_eq!(with_lock.request, initial.request);
+        assert_ne!(with_lock.inputs, initial.inputs);
+        set_mtime("pkg/defs.bzl", 1);
+        let with_bzl = key();
+        assert_ne!(with_bzl.inputs, with_lock.inputs);
+
+        // Directories in .bazelignore are skipped.
+        std::fs::write(workspace.join(".bazelignore"), "# comment\nnode_modules/\n").unwrap();
+        let ignoring = key();
+        set_mtime("node_modules/dep/BUILD.bazel", 1);
+        assert_eq!(key(), ignoring);
+        set_mtime("pkg/BUILD.bazel", 1);
+        assert_ne!(key(), ignoring);
+    }
+
+    #[test]
+    fn collector_consolidates_across_aquery_outputs() {
+        let execution_root = TestDir::new("collector");
+        std::fs::create_dir_all(execution_root.join("specs")).unwrap();
+        std::fs::write(
+            execution_root.join("specs/lib.json"),
+            crate_spec_json("ID-mylib.rs", "mylib", "rlib"),
+        )
+        .unwrap();
+        std::fs::write(
+            execution_root.join("specs/test.json"),
+            crate_spec_json("ID-mylib.rs", "mylib_test", "bin"),
+        )
+        .unwrap();
+
+        let mut collector = CrateSpecCollector::new();
+        collector
+            .add_aquery_output(&execution_root, &aquery_output_for("test.json"))
+            .unwrap();
+
+        // A failing scope leaves previously collected specs intact.
+        std::fs::write(execution_root.join("specs/bad.json"), "{").unwrap();
+        assert!(collector
+            .add_aquery_output(&execution_root, &aquery_output_for("bad.json"))
+            .is_err());
+
+        collector
+            .add_aquery_output(&execution_root, &aquery_output_for("lib.json"))
+            .unwrap();
+
+        let specs = collector.finish(&ConsolidationOptions::default()).unwrap();
+        assert_eq!(specs.len(), 1);
+        let spec = specs.into_iter().next().unwrap();
+        assert_eq!(spec.display_name, "mylib");
+        assert_eq!(spec.crate_type, "rlib");
+    }
+
+    #[test]
+    fn parse_aquery_output_files_errors() {
+        let execution_root = Path::new("/exec_root");
+
+        assert_eq!(
+            parse_aquery_output_files(execution_root, "")
+                .unwrap_err()
+                .to_string(),
+            "Aquery returned no output"
+        );
+        assert_eq!(
+            parse_aquery_output_files(execution_root, "{}")
+                .unwrap_err()
+                .to_string(),
+            "Aquery returned an empty result, are there any Rust targets in the specified paths?."
+        );
+        assert_eq!(
+            parse_aquery_output_files(
+                execution_root,
+                r#"{
+                    "targets": [
+                        {"id": 1, "label": "//pkg:cc_lib", "ruleClassId": 1},
+                        {"id": 2, "label": "//pkg:genrule", "ruleClassId": 2}
+                    ],
+                    "actions": []
+                }"#
+            )
+            .unwrap_err()
+            .to_string(),
+            "Aquery matched 2 targets (e.g. //pkg:cc_lib) but none produced a crate spec, the rust_analyzer_aspect only applies to Rust rules"
+        );
+    }
+
+    #[test]
+    fn parse_aquery_output_files_skips_dangling_ids() {
+        let execution_root = TestDir::new("dangling_ids");
+        std::fs::create_dir_all(execution_root.join("specs")).unwrap();
+        std::fs::write(execution_root.join("specs/mylib.json"), "").unwrap();
+
+        // Target 7, artifact 5 and path fragment 9 were pruned from the output.
+        // Only artifact 3 can be resolved to a file.
+        let output_files = parse_aquery_output_files(
+            &execution_root,
+            r#"{
+                "artifacts": [
+                    {"id": 3, "pathFragmentId": 2},
+                    {"id": 4, "pathFragmentId": 9}
+                ],
+                "actions": [
+                    {"targetId": 7, "outputIds": [3]},
+                    {"targetId": 1, "outputIds": [5, 4]}
+                ],
+                "pathFragments": [
+                    {"id": 1, "label": "specs"},
+                    {"id": 2, "label": "mylib.json", "parentId": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(output_files, vec![execution_root.join("specs/mylib.json")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_aquery_output_files_follows_symlinks() {
+        let dir = TestDir::new("symlinked_exec_root");
+        std::fs::create_dir_all(dir.join("sandbox/specs")).unwrap();
+        std::fs::write(dir.join("sandbox/specs/mylib.json"), "").unwrap();
+        std::fs::create_dir_all(dir.join("execroot")).unwrap();
+        std::os::unix::fs::symlink(dir.join("sandbox/specs"), dir.join("execroot/specs")).unwrap();
+        std::os::unix::fs::symlink(dir.join("execroot"), dir.join("execroot_link")).unwrap();
+
+        for execution_root in [dir.join("execroot"), dir.join("execroot_link")] {
+            let output_files =
+                parse_aquery_output_files(&execution_root, &aquery_output_for("mylib.json"))
+                    .unwrap();
+            assert_eq!(output_files, vec![execution_root.join("specs/mylib.json")]);
+        }
+
+        // Missing files are still skipped.
+        let output_files =
+            parse_aquery_output_files(&dir.join("execroot"), &aquery_output_for("missing.json"))
+                .unwrap();
+        assert!(output_files.is_empty());
+    }
+
+    #[test]
+    fn path_from_fragments_skips_empty_labels() {
+        let fragments = [
+            PathFragment {
+                id: 1,
+                label: "".into(),
+                parent_id: None,
+            },
+            PathFragment {
+                id: 2,
+                label: "bazel-out".into(),
+                parent_id: Some(1),
+            },
+            PathFragment {
+                id: 3,
+                label: "".into(),
+                parent_id: Some(2),
+            },
+            PathFragment {
+                id: 4,
+                label: "spec.json".into(),
+                parent_id: Some(3),
+            },
+        ];
+        let fragments = fragments
+            .iter()
+            .map(|pf| (pf.id, pf))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(
+            path_from_fragments(4, &fragments, &mut HashMap::new()).unwrap(),
+            Path::new("bazel-out").join("spec.json")
+        );
+    }
+
+    #[test]
+    fn path_from_fragments_long_chain() {
+        fn recursive_path(id: u32, fragments: &BTreeMap<u32, &PathFragment>) -> PathBuf {
+            let path_fragment = fragments[&id];
+            let mut buf = match path_fragment.parent_id {
+                Some(parent_id) => recursive_path(parent_id, fragments),
+                None => PathBuf::new(),
+            };
+            buf.push(&path_fragment.label);
+            buf
+        }
+
+        let fragments: Vec<PathFragment> = (1..=500)
+            .map(|id| PathFragment {
+                id,
+                label: format!("dir{id}"),
+                parent_id: (id > 1).then(|| id - 1),
+            })
+            .collect();
+        let fragments = fragments
+            .iter()
+            .map(|pf| (pf.id, pf))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut cache = HashMap::new();
+        for id in [250, 500, 1] {
+            assert_eq!(
+                path_from_fragments(id, &fragments, &mut cache).unwrap(),
+                recursive_path(id, &fragments)
+            );
+        }
+        assert_eq!(cache.len(), 500);
+    }
+
+    #[test]
+    fn path_from_fragments_rejects_cycles() {
+        let fragments = [
+            PathFragment {
+                id: 1,
+                label: "a".into(),
+                parent_id: Some(2),
+            },
+            PathFragment {
+                id: 2,
+                label: "b".into(),
+                parent_id: Some(1),
+            },
+        ];
+        let fragments = fragments
+            .iter()
+            .map(|pf| (pf.id, pf))
+            .collect::<BTreeMap<_, _>>();
+
+        assert!(path_from_fragments(1, &fragments, &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn consolidate_by_root_module_and_display_name() {
+        let spec = |crate_id: &str, display_name: &str, deps: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: crate_id.into(),
+            display_name: display_name.into(),
+            bazel_target: format!("//:{display_name}"),
+            edition: "2018".into(),
+            root_module: format!("{display_name}.rs"),
+            is_workspace_member: true,
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crate_specs = vec![
+            spec("ID-k8-fastbuild/mylib.rs", "mylib", &[]),
+            spec("ID-k8-opt/mylib.rs", "mylib", &[]),
+            spec("ID-user.rs", "user", &["ID-k8-opt/mylib.rs"]),
+        ];
+
+        // By default the variants are distinct crates.
+        assert_eq!(
+            consolidate_crate_specs(crate_specs.clone(), &ConsolidationOptions::default(), None)
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let consolidated = consolidate_crate_specs(
+            crate_specs,
+            &ConsolidationOptions {
+                key: ConsolidationKey::RootModuleAndDisplayName,
+                ..ConsolidationOptions::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            consolidated
+                .iter()
+                .map(|c| (
+                    c.crate_id.as_str(),
+                    c.deps.iter().cloned().collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("ID-k8-fastbuild/mylib.rs", vec![]),
+                ("ID-user.rs", vec!["ID-k8-fastbuild/mylib.rs".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn consolidate_by_root_module_and_display_name_conflicts() {
+        let spec = |crate_id: &str, edition: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: crate_id.into(),
+            display_name: "mylib".into(),
+            bazel_target: "//:mylib".into(),
+            edition: edition.into(),
+            root_module: "mylib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let err = consolidate_crate_specs(
+            vec![spec("ID-a/mylib.rs", "2018"), spec("ID-b/mylib.rs", "2021")],
+            &ConsolidationOptions {
+                strict: true,
+                key: ConsolidationKey::RootModuleAndDisplayName,
+            },
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Crate specs with the same root_module and display_name have incompatible metadata:\n  \
+             mylib (mylib.rs): conflicting editions {\"2018\", \"2021\"}"
+        );
+    }
+
+    #[test]
+    fn consolidate_conflicting_editions() {
+        let spec = |display_name: &str, edition: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: "ID-mylib.rs".into(),
+            display_name: display_name.into(),
+            bazel_target: format!("//:{display_name}"),
+            edition: edition.into(),
+            root_module: "mylib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crate_specs = vec![spec("mylib", "2018"), spec("mylib_2021", "2021")];
+
+        let err = consolidate_crate_specs(
+            crate_specs.clone(),
+            &ConsolidationOptions {
+                strict: true,
+                ..ConsolidationOptions::default()
+            },
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Crate specs with the same crate_id have incompatible metadata:\n  \
+             ID-mylib.rs: conflicting editions {\"2018\", \"2021\"}"
+        );
+
+        // Lenient mode warns and merges anyway.
+        assert_eq!(
+            consolidate_crate_specs(crate_specs, &ConsolidationOptions::default(), None)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn consolidate_conflicting_root_modules() {
+        let spec = |name: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: "ID-collision".into(),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crate_specs = vec![spec("a"), spec("b")];
+
+        // Lenient mode only warns.
+        assert!(check_conflicts(&crate_specs, &ConsolidationOptions::default()).is_ok());
+        let err = consolidate_crate_specs(
+            crate_specs,
+            &ConsolidationOptions {
+                strict: true,
+                ..ConsolidationOptions::default()
+            },
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Crate specs with the same crate_id have incompatible metadata:\n  \
+             ID-collision: conflicting root modules {\"a/lib.rs\", \"b/lib.rs\"}"
+        );
+    }
 }
@@ -1,35 +1,498 @@
 use std::collections::HashMap;
-use std::path::Path;
-use std::process::Command;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use runfiles::Runfiles;
 
 mod aquery;
 mod rust_project;
+#[cfg(test)]
+mod test_util;
 
+pub use aquery::{ConsolidationKey, ConsolidationOptions, CrateSpec, CrateSpecCollector};
+pub use rust_project::{
+    crates_owning_file, diff_projects, render_rust_project, sysroot_only_project,
+    write_rust_project_to, CrateDiff, CrateKey, CrateOrdering, DependencyKey, ProjectDiff,
+    ProjectSummary, RustProject, RustProjectOptions,
+};
+
+/// Flags passed to every Bazel invocation so that captured output is plain text
+/// free of progress messages and terminal control sequences.
+pub const BAZEL_OUTPUT_FLAGS: &[&str] = &["--noshow_progress", "--color=no", "--curses=no"];
+
+/// Returns a [Command] running `bazel`, or the binary named by `BAZEL_REAL` when a
+/// wrapper script has set it, in `workspace`. Variables set by `bazel run` are removed
+/// so the nested invocation behaves as if it had been run from a shell.
+pub fn bazel_command(bazel: &Path, workspace: Option<&Path>) -> Command {
+    let mut command = match std::env::var_os("BAZEL_REAL") {
+        Some(bazel_real) if !bazel_real.is_empty() => Command::new(bazel_real),
+        _ => Command::new(bazel),
+    };
+    command
+        .env_remove("BAZELISK_SKIP_WRAPPER")
+        .env_remove("BUILD_WORKING_DIRECTORY")
+        .env_remove("BUILD_WORKSPACE_DIRECTORY");
+    if let Some(workspace) = workspace {
+        command.current_dir(workspace);
+    }
+    command
+}
+
+/// Runs `bazel info <key>` in `workspace` and returns the path it prints, e.g. for
+/// `execution_root` or `output_base`.
+pub fn bazel_info(bazel: &Path, workspace: Option<&Path>, key: &str) -> anyhow::Result<PathBuf> {
+    parse_bazel_info_path(key, &run_bazel_info(bazel, workspace, &[key])?)
+}
+
+/// Runs `bazel info` for `keys` in `workspace` and returns its stdout.
+fn run_bazel_info(bazel: &Path, workspace: Option<&Path>, keys: &[&str]) -> anyhow::Result<String> {
+    let mut command = bazel_command(bazel, workspace);
+    command.arg("info").args(BAZEL_OUTPUT_FLAGS).args(keys);
+
+    let output = output_with_timeout(&mut command, command_timeout()?)?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to run `bazel info {}` ({:?}): {}",
+            keys.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the output of `bazel info <key>`, which is the value on a single line.
+fn parse_bazel_info_path(key: &str, stdout: &str) -> anyhow::Result<PathBuf> {
+    let mut lines = output_lines(stdout).map(str::trim);
+    match (lines.next(), lines.next()) {
+        (Some(value), None) => Ok(PathBuf::from(value)),
+        _ => Err(anyhow!(
+            "Expected a single line from `bazel info {key}`, got: {stdout:?}"
+        )),
+    }
+}
+
+/// Parses the output of `bazel info <key>...` for several keys, which is a
+/// `key: value` line for each of them.
+fn parse_bazel_info_paths(keys: &[&str], stdout: &str) -> anyhow::Result<HashMap<String, PathBuf>> {
+    let mut paths = HashMap::new();
+    for line in output_lines(stdout) {
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Unexpected line in `bazel info` output: {line:?}"))?;
+        paths.insert(key.trim().to_owned(), PathBuf::from(value.trim()));
+    }
+    if let Some(missing) = keys.iter().find(|&&key| !paths.contains_key(key)) {
+        return Err(anyhow!(
+            "`bazel info` did not report `{missing}`, got: {stdout:?}"
+        ));
+    }
+    Ok(paths)
+}
+
+/// Returns the `workspace`, `execution_root` and `output_base`, asking `bazel info`
+/// for whichever weren't provided in a single invocation. Editors generally don't
+/// know them.
+pub fn bazel_output_paths(
+    bazel: &Path,
+    workspace: Option<PathBuf>,
+    execution_root: Option<PathBuf>,
+    output_base: Option<PathBuf>,
+) -> anyhow::Result<(PathBuf, PathBuf, PathBuf)> {
+    let provided = [
+        ("workspace", workspace),
+        ("execution_root", execution_root),
+        ("output_base", output_base),
+    ];
+    let missing: Vec<&str> = provided
+        .iter()
+        .filter(|(_, path)| path.is_none())
+        .map(|(key, _)| *key)
+        .collect();
+
+    // Run in the provided workspace, if any, so `bazel info` reports on it.
+    let mut info = match missing.as_slice() {
+        [] => HashMap::new(),
+        [key] => HashMap::from([(
+            key.to_string(),
+            bazel_info(bazel, provided[0].1.as_deref(), key)?,
+        )]),
+        keys => parse_bazel_info_paths(
+            keys,
+            &run_bazel_info(bazel, provided[0].1.as_deref(), keys)?,
+        )?,
+    };
+
+    let mut resolve = |(key, path): (&str, Option<PathBuf>)| {
+        path.or_else(|| info.remove(key))
+            .ok_or_else(|| anyhow!("`bazel info` did not report `{key}`"))
+    };
+    let [workspace, execution_root, output_base] = provided;
+    Ok((
+        resolve(workspace)?,
+        resolve(execution_root)?,
+        resolve(output_base)?,
+    ))
+}
+
+/// The environment variable selecting the log format. When set to `json`, log
+/// records are written to stderr as JSON objects by [JsonLogger].
+pub const LOG_FORMAT_ENV_VAR: &str = "RULES_RUST_RA_LOG_FORMAT";
+
+/// A [log::Log] writing each record as a single line JSON object such as
+/// `{"level":"WARN","msg":"...","target":"gen_rust_project_lib::aquery"}`. Clients
+/// running the tool as a subprocess can parse these reliably or ignore them.
+pub struct JsonLogger<W> {
+    level: log::LevelFilter,
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    pub fn new(level: log::LevelFilter, writer: W) -> Self {
+        Self {
+            level,
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl JsonLogger<std::io::Stderr> {
+    /// Installs a [JsonLogger] writing to stderr as the global logger. The level is
+    /// taken from `RUST_LOG` (e.g. `debug`), defaulting to `error`.
+    pub fn init() -> anyhow::Result<()> {
+        let level = match std::env::var("RUST_LOG") {
+            Ok(level) => level
+                .parse()
+                .map_err(|_| anyhow!("Invalid RUST_LOG level `{level}` for JSON logging"))?,
+            Err(_) => log::LevelFilter::Error,
+        };
+        log::set_boxed_logger(Box::new(Self::new(level, std::io::stderr())))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> log::Log for JsonLogger<W> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = serde_json::json!({
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "msg": record.args().to_string(),
+        });
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(writer, "{line}");
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush();
+    }
+}
+
+/// The environment variable setting the number of seconds a Bazel invocation may
+/// take before it is killed, see [command_timeout].
+pub const TIMEOUT_ENV_VAR: &str = "RULES_RUST_RA_TIMEOUT_SECS";
+
+/// Returns the timeout for Bazel invocations set with [TIMEOUT_ENV_VAR], or `None`
+/// if it is unset, in which case invocations may run indefinitely.
+pub fn command_timeout() -> anyhow::Result<Option<Duration>> {
+    match std::env::var(TIMEOUT_ENV_VAR) {
+        Ok(secs) => secs
+            .trim()
+            .parse()
+            .map(|secs| Some(Duration::from_secs(secs)))
+            .map_err(|err| anyhow!("Invalid {TIMEOUT_ENV_VAR} `{secs}`: {err}")),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(anyhow!("Invalid {TIMEOUT_ENV_VAR}: {err}")),
+    }
+}
+
+/// Runs `command` to completion like [Command::output], killing it if it runs
+/// longer than `timeout`. A Bazel server blocked on another client holding its
+/// lock would otherwise hang the caller with no feedback.
+pub fn output_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Output> {
+    let Some(timeout) = timeout else {
+        return Ok(command.output()?);
+    };
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Drain the pipes while waiting so a chatty child can't block on a full pipe.
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "{:?} timed out after {}s, is another Bazel command holding the lock?",
+                command,
+                timeout.as_secs_f64()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().expect("stdout reader panicked"),
+        stderr: stderr.join().expect("stderr reader panicked"),
+    })
+}
+
+/// Reads `pipe` to the end on a separate thread.
+fn read_in_background(
+    pipe: Option<impl Read + Send + 'static>,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// The byte order mark some Windows tools write at the start of UTF-8 output.
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+/// Splits the output of a command, e.g. Bazel, into lines without their line
+/// terminators, skipping blank lines. On Windows the output may use CRLF line endings
+/// and start with a byte order mark, neither of which may end up in labels or paths.
+pub fn output_lines(output: &str) -> impl Iterator<Item = &str> {
+    output
+        .strip_prefix(BYTE_ORDER_MARK)
+        .unwrap_or(output)
+        .lines()
+        .map(|line| line.trim_end_matches(['\r', '\n']))
+        .filter(|line| !line.trim().is_empty())
+}
+
+/// Reads a newline-delimited list of target patterns, e.g. from stdin or a file,
+/// for lists too long to pass as arguments. Lines are trimmed, and blank lines and
+/// lines starting with `#` are skipped.
+pub fn read_targets(reader: impl BufRead) -> anyhow::Result<Vec<String>> {
+    let mut targets = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = match index {
+            0 => line.strip_prefix(BYTE_ORDER_MARK).unwrap_or(&line),
+            _ => &line,
+        };
+        let target = line.trim();
+        if target.is_empty() || target.starts_with('#') {
+            continue;
+        }
+        targets.push(target.to_owned());
+    }
+    Ok(targets)
+}
+
+/// Returns the label of the aspect generating crate specs: `aspect` if given, or the
+/// `rust_analyzer_aspect` of `rules_rust_name` otherwise. `aspect` must be of the
+/// form `label%symbol`, as accepted by `bazel build --aspects`.
+pub fn rust_analyzer_aspect(rules_rust_name: &str, aspect: Option<&str>) -> anyhow::Result<String> {
+    let Some(aspect) = aspect else {
+        return Ok(format!(
+            "{rules_rust_name}//rust:defs.bzl%rust_analyzer_aspect"
+        ));
+    };
+
+    let valid = match aspect.split_once('%') {
+        Some((label, symbol)) => {
+            label.contains("//")
+                && label.ends_with(".bzl")
+                && symbol
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && symbol
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(anyhow!(
+            "Invalid aspect `{aspect}`: expected `<label of a .bzl file>%<aspect name>`, e.g. `@rules_rust//rust:defs.bzl%rust_analyzer_aspect`"
+        ));
+    }
+
+    Ok(aspect.to_owned())
+}
+
+/// The output groups built by [generate_crate_info] unless others are requested: the
+/// crate specs along with the generated sources and proc-macro dylibs they refer to.
+pub const DEFAULT_OUTPUT_GROUPS: &[&str] = &[
+    "rust_analyzer_crate_spec",
+    "rust_generated_srcs",
+    "rust_analyzer_proc_macro_dylib",
+];
+
+/// Builds the crate spec files for `targets`. `extra_args` are passed to `bazel build`
+/// after the flags needed to run the aspect, e.g. to select a `--config`. Fails if
+/// `targets` is empty rather than letting Bazel fall back to its default patterns.
+/// `aspect` overrides the default aspect, see [rust_analyzer_aspect]. `output_groups`
+/// are the output groups to build, usually [DEFAULT_OUTPUT_GROUPS] plus any produced
+/// by a custom aspect.
+#[allow(clippy::too_many_arguments)]
 pub fn generate_crate_info(
     bazel: impl AsRef<Path>,
     workspace: impl AsRef<Path>,
     rules_rust: impl AsRef<str>,
+    aspect: Option<&str>,
+    targets: &[String],
+    compilation_mode: Option<&str>,
+    extra_args: &[String],
+    output_groups: &[String],
+) -> anyhow::Result<()> {
+    build_crate_info(
+        bazel.as_ref(),
+        workspace.as_ref(),
+        &rust_analyzer_aspect(rules_rust.as_ref(), aspect)?,
+        targets,
+        compilation_mode,
+        extra_args,
+        output_groups,
+        None,
+    )
+}
+
+/// Like [generate_crate_info], but also returns the crate spec files that were built,
+/// relative to the execution root, as reported in Bazel's build event stream. Reading
+/// these directly saves the `bazel aquery` otherwise run to find them.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_crate_info_files(
+    bazel: impl AsRef<Path>,
+    workspace: impl AsRef<Path>,
+    rules_rust: impl AsRef<str>,
+    aspect: Option<&str>,
+    targets: &[String],
+    compilation_mode: Option<&str>,
+    extra_args: &[String],
+    output_groups: &[String],
+) -> anyhow::Result<Vec<PathBuf>> {
+    // Bazel overwrites the build event file, so it goes in a new directory only we can
+    // write to rather than at a predictable path in the shared temp dir.
+    let build_events_dir =
+        create_private_dir(&std::env::temp_dir(), "gen_rust_project_build_events")
+            .map_err(|e| anyhow!("Failed to create a directory for build events: {e}"))?;
+    let build_events = build_events_dir.join("build_events.json");
+    let result = build_crate_info(
+        bazel.as_ref(),
+        workspace.as_ref(),
+        &rust_analyzer_aspect(rules_rust.as_ref(), aspect)?,
+        targets,
+        compilation_mode,
+        extra_args,
+        output_groups,
+        Some(&build_events),
+    )
+    .and_then(|()| {
+        std::fs::read_to_string(&build_events).map_err(|e| {
+            anyhow!(
+                "Failed to read build events from {}: {e}",
+                build_events.display()
+            )
+        })
+    });
+    // The stream is only needed until it's parsed, and may not exist on failure.
+    let _ = std::fs::remove_dir_all(&build_events_dir);
+
+    aquery::parse_build_event_crate_spec_files(&result?)
+}
+
+/// Creates a new, uniquely named directory under `parent` that only the current user
+/// can access. Fails rather than reusing a path that already exists, so the directory
+/// can't be one planted by someone else.
+fn create_private_dir(parent: &Path, prefix: &str) -> std::io::Result<PathBuf> {
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    let mut attempts = 0;
+    loop {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let dir = parent.join(format!(
+            "{prefix}_{}_{nanos:08x}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && attempts < 100 => {
+                attempts += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_crate_info(
+    bazel: &Path,
+    workspace: &Path,
+    aspect: &str,
     targets: &[String],
+    compilation_mode: Option<&str>,
+    extra_args: &[String],
+    output_groups: &[String],
+    build_events: Option<&Path>,
 ) -> anyhow::Result<()> {
+    if targets.is_empty() {
+        return Err(anyhow!("no targets provided to generate crate info"));
+    }
+    if output_groups.is_empty() {
+        return Err(anyhow!("no output groups provided to generate crate info"));
+    }
+
     log::debug!("Building rust_analyzer_crate_spec files for {:?}", targets);
 
-    let output = Command::new(bazel.as_ref())
-        .current_dir(workspace.as_ref())
-        .env_remove("BAZELISK_SKIP_WRAPPER")
-        .env_remove("BUILD_WORKING_DIRECTORY")
-        .env_remove("BUILD_WORKSPACE_DIRECTORY")
-        .arg("build")
-        .arg("--norun_validations")
-        .arg(format!(
-            "--aspects={}//rust:defs.bzl%rust_analyzer_aspect",
-            rules_rust.as_ref()
-        ))
-        .arg("--output_groups=rust_analyzer_crate_spec,rust_generated_srcs")
-        .args(targets)
-        .output()?;
+    let output = output_with_timeout(
+        &mut crate_info_command(
+            bazel,
+            workspace,
+            aspect,
+            targets,
+            compilation_mode,
+            extra_args,
+            output_groups,
+            build_events,
+        ),
+        command_timeout()?,
+    )?;
 
     if !output.status.success() {
         return Err(anyhow!(
@@ -42,6 +505,38 @@ pub fn generate_crate_info(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+fn crate_info_command(
+    bazel: &Path,
+    workspace: &Path,
+    aspect: &str,
+    targets: &[String],
+    compilation_mode: Option<&str>,
+    extra_args: &[String],
+    output_groups: &[String],
+    build_events: Option<&Path>,
+) -> Command {
+    let mut command = bazel_command(bazel, Some(workspace));
+    command
+        .arg("build")
+        .args(BAZEL_OUTPUT_FLAGS)
+        .arg("--norun_validations")
+        .args(compilation_mode.map(|mode| format!("--compilation_mode={mode}")))
+        .arg(format!("--aspects={aspect}"))
+        .arg(format!("--output_groups={}", output_groups.join(",")))
+        .args(build_events.map(|path| {
+            let mut arg = std::ffi::OsString::from("--build_event_json_file=");
+            arg.push(path);
+            arg
+        }))
+        .args(extra_args)
+        .args(targets);
+    command
+}
+
+/// Writes `rust-project.json` for `targets` and returns a summary of any quality
+/// issues found in the generated project.
+#[allow(clippy::too_many_arguments)]
 pub fn write_rust_project(
     bazel: impl AsRef<Path>,
     workspace: impl AsRef<Path>,
@@ -50,34 +545,647 @@ pub fn write_rust_project(
     execution_root: impl AsRef<Path>,
     output_base: impl AsRef<Path>,
     rust_project_path: impl AsRef<Path>,
-) -> anyhow::Result<()> {
-    let crate_specs = aquery::get_crate_specs(
+    options: &RustProjectOptions,
+) -> anyhow::Result<ProjectSummary> {
+    let mut rust_project = generate_rust_project(
         bazel.as_ref(),
         workspace.as_ref(),
-        execution_root.as_ref(),
+        rules_rust_name.as_ref(),
         targets,
+        execution_root.as_ref(),
+        output_base.as_ref(),
+        options,
+    )?;
+
+    if options.preserve_sysroot {
+        rust_project::preserve_existing_sysroot(&mut rust_project, rust_project_path.as_ref())?;
+    }
+
+    let written = rust_project::write_rust_project(
+        rust_project_path.as_ref(),
+        execution_root.as_ref(),
+        output_base.as_ref(),
+        &rust_project,
+    )?;
+
+    // An unchanged rust-project.json means the crate labels are unchanged too, and
+    // there's nothing for the hook to tell the editor to reload.
+    if let Some(crate_labels_path) = &options.crate_labels_path {
+        if written || !crate_labels_path.exists() {
+            rust_project::write_crate_labels(crate_labels_path, &rust_project)?;
+        }
+    }
+
+    if written {
+        if let Some(hook) = &options.post_generation_hook {
+            run_post_generation_hook(hook, workspace.as_ref(), rust_project_path.as_ref())?;
+        }
+    }
+
+    Ok(ProjectSummary {
+        unchanged: !written,
+        ..rust_project::summarize_rust_project(
+            &rust_project,
+            workspace.as_ref(),
+            execution_root.as_ref(),
+            output_base.as_ref(),
+        )
+    })
+}
+
+/// Runs `hook` with the path to the freshly written `rust-project.json`.
+fn run_post_generation_hook(
+    hook: &Path,
+    workspace: &Path,
+    rust_project_path: &Path,
+) -> anyhow::Result<()> {
+    log::debug!("Running post generation hook {}", hook.display());
+
+    let output = Command::new(hook)
+        .current_dir(workspace)
+        .arg(rust_project_path)
+        .output()
+        .map_err(|err| {
+            anyhow!(
+                "Failed to run post generation hook {}: {}",
+                hook.display(),
+                err
+            )
+        })?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "post generation hook {} failed:({})\n{}",
+            hook.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Like [write_rust_project] but prints the `rust-project.json` content to stdout
+/// instead of writing it to disk.
+pub fn print_rust_project(
+    bazel: impl AsRef<Path>,
+    workspace: impl AsRef<Path>,
+    rules_rust_name: &impl AsRef<str>,
+    targets: &[String],
+    execution_root: impl AsRef<Path>,
+    output_base: impl AsRef<Path>,
+    options: &RustProjectOptions,
+) -> anyhow::Result<ProjectSummary> {
+    let rust_project = generate_rust_project(
+        bazel.as_ref(),
+        workspace.as_ref(),
         rules_rust_name.as_ref(),
+        targets,
+        execution_root.as_ref(),
+        output_base.as_ref(),
+        options,
     )?;
 
-    let path = runfiles::rlocation!(
-        Runfiles::create()?,
-        "rules_rust/rust/private/rust_analyzer_detect_sysroot.rust_analyzer_toolchain.json"
-    )
-    .unwrap();
+    rust_project::print_rust_project(execution_root.as_ref(), output_base.as_ref(), &rust_project)?;
+
+    Ok(rust_project::summarize_rust_project(
+        &rust_project,
+        workspace.as_ref(),
+        execution_root.as_ref(),
+        output_base.as_ref(),
+    ))
+}
+
+/// Generates the [RustProject] for `targets` without writing it, e.g. to query it
+/// with [crates_owning_file].
+pub fn generate_rust_project(
+    bazel: &Path,
+    workspace: &Path,
+    rules_rust_name: &str,
+    targets: &[String],
+    execution_root: &Path,
+    output_base: &Path,
+    options: &RustProjectOptions,
+) -> anyhow::Result<rust_project::RustProject> {
+    let aspect = rust_analyzer_aspect(rules_rust_name, options.aspect.as_deref())?;
+    let get_crate_specs = || {
+        aquery::get_crate_specs(
+            bazel,
+            workspace,
+            execution_root,
+            targets,
+            &aspect,
+            options.compilation_mode.as_deref(),
+            &options.bazel_args,
+            options.no_deps,
+            &options.consolidation,
+        )
+    };
+    let crate_specs = if let Some(crate_spec_dir) = &options.crate_spec_dir {
+        aquery::get_crate_specs_from_dir(crate_spec_dir, execution_root, &options.consolidation)?
+    } else if let Some(crate_spec_files) = &options.crate_spec_files {
+        aquery::get_crate_specs_from_files(
+            crate_spec_files,
+            execution_root,
+            &options.consolidation,
+        )?
+    } else if options.cache_crate_specs {
+        let key = aquery::crate_specs_cache_key(
+            workspace,
+            targets,
+            &format!(
+                "{aspect} {:?} {:?} {:?} {}",
+                options.compilation_mode,
+                options.bazel_args,
+                options.consolidation,
+                options.no_deps
+            ),
+        );
+        aquery::cached_crate_specs(
+            &output_base.join("rules_rust_analyzer_cache"),
+            key,
+            options.refresh_crate_spec_cache,
+            get_crate_specs,
+        )?
+    } else {
+        get_crate_specs()?
+    };
+
+    let path = match &options.toolchain_info {
+        Some(path) => path.clone(),
+        None => toolchain_info_path(rules_rust_name, options.compilation_mode.as_deref())?,
+    };
     let toolchain_info: HashMap<String, String> =
         serde_json::from_str(&std::fs::read_to_string(path)?)?;
 
     let sysroot_src = &toolchain_info["sysroot_src"];
     let sysroot = &toolchain_info["sysroot"];
 
-    let rust_project = rust_project::generate_rust_project(sysroot, sysroot_src, &crate_specs)?;
+    let workspace_str = workspace
+        .to_str()
+        .ok_or_else(|| anyhow!("workspace is not valid UTF-8"))?;
+    let mut rust_project = rust_project::generate_rust_project(
+        sysroot,
+        sysroot_src,
+        workspace_str,
+        &crate_specs,
+        options,
+    )?;
+
+    rust_project::resolve_env_placeholders(&mut rust_project, execution_root, output_base);
 
-    rust_project::write_rust_project(
-        rust_project_path.as_ref(),
-        execution_root.as_ref(),
-        output_base.as_ref(),
+    if options.resolve_symlinks {
+        rust_project::resolve_symlinks(&mut rust_project, execution_root, output_base);
+    }
+
+    rust_project::drop_missing_proc_macro_dylibs(
+        &mut rust_project,
+        workspace,
+        execution_root,
+        output_base,
+    );
+    rust_project::check_sysroot(
         &rust_project,
+        execution_root,
+        output_base,
+        options.require_sysroot,
     )?;
+    rust_project::check_duplicate_display_names(&rust_project);
 
-    Ok(())
+    Ok(rust_project)
+}
+
+/// Returns the runfiles path of the toolchain info file in the rules_rust repository
+/// named `rules_rust_name` (e.g. `@rules_rust`, or empty within rules_rust itself),
+/// specific to `compilation_mode` if given.
+fn toolchain_info_rlocation(rules_rust_name: &str, compilation_mode: Option<&str>) -> String {
+    let repository = match rules_rust_name.trim_start_matches('@') {
+        "" => "rules_rust",
+        repository => repository,
+    };
+    match compilation_mode {
+        Some(mode) => format!(
+            "{repository}/rust/private/rust_analyzer_detect_sysroot.{mode}.rust_analyzer_toolchain.json"
+        ),
+        None => format!(
+            "{repository}/rust/private/rust_analyzer_detect_sysroot.rust_analyzer_toolchain.json"
+        ),
+    }
+}
+
+/// Locates the toolchain info file describing the sysroot. A file specific to
+/// `compilation_mode` (e.g. `rust_analyzer_detect_sysroot.opt.rust_analyzer_toolchain.json`)
+/// is preferred when present, so std is analyzed as it is built in that mode.
+fn toolchain_info_path(
+    rules_rust_name: &str,
+    compilation_mode: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let runfiles = Runfiles::create()?;
+    find_toolchain_info(rules_rust_name, compilation_mode, |rlocation| {
+        runfiles::rlocation!(runfiles, rlocation).filter(|path| path.exists())
+    })
+}
+
+/// Looks up the toolchain info for `compilation_mode` with `rlocation`, falling back
+/// to the default toolchain info.
+fn find_toolchain_info(
+    rules_rust_name: &str,
+    compilation_mode: Option<&str>,
+    rlocation: impl Fn(&str) -> Option<PathBuf>,
+) -> anyhow::Result<PathBuf> {
+    if let Some(mode) = compilation_mode {
+        match rlocation(&toolchain_info_rlocation(rules_rust_name, Some(mode))) {
+            Some(path) => return Ok(path),
+            None => log::debug!(
+                "No toolchain info for compilation mode `{mode}`, using the default toolchain info"
+            ),
+        }
+    }
+
+    let default = toolchain_info_rlocation(rules_rust_name, None);
+    rlocation(&default).ok_or_else(|| {
+        anyhow!("Failed to locate the rust-analyzer toolchain info at runfile {default}")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bazel_command_environment() {
+        let command = bazel_command(Path::new("bazel"), Some(Path::new("/workspace")));
+
+        assert_eq!(command.get_current_dir(), Some(Path::new("/workspace")));
+        let removed: Vec<_> = command
+            .get_envs()
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| key.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            removed,
+            [
+                "BAZELISK_SKIP_WRAPPER",
+                "BUILD_WORKING_DIRECTORY",
+                "BUILD_WORKSPACE_DIRECTORY"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_bazel_info_path_single_line() {
+        assert_eq!(
+            parse_bazel_info_path(
+                "execution_root",
+                "/home/user/.cache/bazel/_bazel_user/0123abcd/execroot/_main\n"
+            )
+            .unwrap(),
+            PathBuf::from("/home/user/.cache/bazel/_bazel_user/0123abcd/execroot/_main")
+        );
+
+        let err = parse_bazel_info_path(
+            "output_base",
+            "execution_root: /execroot/_main\noutput_base: /output_base\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Expected a single line from `bazel info output_base`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_bazel_info_paths_for_several_keys() {
+        let paths = parse_bazel_info_paths(
+            &["workspace", "output_base"],
+            "workspace: /home/user/project\r\noutput_base: C:\\bazel\\output_base\r\n",
+        )
+        .unwrap();
+        assert_eq!(paths["workspace"], PathBuf::from("/home/user/project"));
+        assert_eq!(
+            paths["output_base"],
+            PathBuf::from("C:\\bazel\\output_base")
+        );
+
+        let err =
+            parse_bazel_info_paths(&["workspace"], "Starting local Bazel server\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Unexpected line in `bazel info` output"),
+            "{}",
+            err
+        );
+
+        let err = parse_bazel_info_paths(
+            &["workspace", "output_base"],
+            "workspace: /home/user/project\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("`bazel info` did not report `output_base`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn create_private_dir_is_new_and_private() {
+        let parent = crate::test_util::TestDir::new("private_dir");
+        let a = create_private_dir(&parent, "events").unwrap();
+        let b = create_private_dir(&parent, "events").unwrap();
+        assert_ne!(a, b);
+        assert!(a.is_dir() && b.is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&a).unwrap().permissions().mode() & 0o777,
+                0o700
+            );
+        }
+    }
+
+    #[test]
+    fn generate_crate_info_requires_targets() {
+        let err = generate_crate_info(
+            "/nonexistent/bazel",
+            "/nonexistent/workspace",
+            "@rules_rust",
+            None,
+            &[],
+            None,
+            &[],
+            &["rust_analyzer_crate_spec".to_owned()],
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "no targets provided to generate crate info"
+        );
+
+        let err = generate_crate_info(
+            "/nonexistent/bazel",
+            "/nonexistent/workspace",
+            "@rules_rust",
+            None,
+            &["//...".to_owned()],
+            None,
+            &[],
+            &[],
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "no output groups provided to generate crate info"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_with_timeout_kills_slow_commands() {
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let start = Instant::now();
+        let err = output_with_timeout(&mut command, Some(Duration::from_millis(100))).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.to_string()
+                .starts_with(r#""sleep" "5" timed out after 0.1s"#),
+            "{}",
+            err
+        );
+
+        let mut command = Command::new("echo");
+        command.arg("done");
+        let output = output_with_timeout(&mut command, Some(Duration::from_secs(5))).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
+    }
+
+    #[test]
+    fn json_logger_writes_one_object_per_line() {
+        use log::Log;
+
+        let logger = JsonLogger::new(log::LevelFilter::Warn, Vec::new());
+        for (level, msg) in [
+            (
+                log::Level::Warn,
+                "Skipping missing crate_spec file: \"a\nb\"",
+            ),
+            (log::Level::Debug, "filtered out"),
+            (log::Level::Error, "done"),
+        ] {
+            logger.log(
+                &log::Record::builder()
+                    .level(level)
+                    .target("gen_rust_project")
+                    .args(format_args!("{}", msg))
+                    .build(),
+            );
+        }
+
+        let output = String::from_utf8(logger.writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({
+                    "level": "WARN",
+                    "target": "gen_rust_project",
+                    "msg": "Skipping missing crate_spec file: \"a\nb\"",
+                }),
+                serde_json::json!({"level": "ERROR", "target": "gen_rust_project", "msg": "done"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn toolchain_info_rlocation_uses_repository_name() {
+        assert_eq!(
+            toolchain_info_rlocation("@my_rules_rust", None),
+            "my_rules_rust/rust/private/rust_analyzer_detect_sysroot.rust_analyzer_toolchain.json"
+        );
+        assert_eq!(
+            toolchain_info_rlocation("@@rules_rust~0.40.0", Some("opt")),
+            "rules_rust~0.40.0/rust/private/rust_analyzer_detect_sysroot.opt.rust_analyzer_toolchain.json"
+        );
+        assert_eq!(
+            toolchain_info_rlocation("", None),
+            "rules_rust/rust/private/rust_analyzer_detect_sysroot.rust_analyzer_toolchain.json"
+        );
+    }
+
+    #[test]
+    fn find_toolchain_info_prefers_compilation_mode() {
+        let available = [
+            "rules_rust/rust/private/rust_analyzer_detect_sysroot.rust_analyzer_toolchain.json",
+            "rules_rust/rust/private/rust_analyzer_detect_sysroot.opt.rust_analyzer_toolchain.json",
+        ];
+        let rlocation = |path: &str| available.contains(&path).then(|| PathBuf::from(path));
+
+        assert_eq!(
+            find_toolchain_info("@rules_rust", Some("opt"), rlocation).unwrap(),
+            PathBuf::from(available[1])
+        );
+        // Modes without their own toolchain info fall back to the default one.
+        assert_eq!(
+            find_toolchain_info("@rules_rust", Some("dbg"), rlocation).unwrap(),
+            PathBuf::from(available[0])
+        );
+        assert_eq!(
+            find_toolchain_info("@rules_rust", None, rlocation).unwrap(),
+            PathBuf::from(available[0])
+        );
+
+        let err = find_toolchain_info("@rules_rust", Some("opt"), |_| None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Failed to locate the rust-analyzer toolchain info at runfile {}",
+                available[0]
+            )
+        );
+    }
+
+    #[test]
+    fn read_targets_skips_comments_and_blanks() {
+        let input = "# Rust targets\n//foo/...\n\n   //bar:baz  \n\t# indented comment\r\n@crates//:serde\r\n";
+
+        assert_eq!(
+            read_targets(input.as_bytes()).unwrap(),
+            ["//foo/...", "//bar:baz", "@crates//:serde"]
+        );
+    }
+
+    #[test]
+    fn output_lines_strips_crlf_and_bom() {
+        let output = "\u{feff}//pkg:target\r\n//pkg:other\r\n\r\n@crates//:serde\r\n";
+
+        assert_eq!(
+            output_lines(output).collect::<Vec<_>>(),
+            ["//pkg:target", "//pkg:other", "@crates//:serde"]
+        );
+        assert_eq!(
+            read_targets(output.as_bytes()).unwrap(),
+            ["//pkg:target", "//pkg:other", "@crates//:serde"]
+        );
+        assert_eq!(
+            parse_bazel_info_path("output_base", "\u{feff}C:\\bazel\\output_base\r\n").unwrap(),
+            PathBuf::from("C:\\bazel\\output_base")
+        );
+    }
+
+    #[test]
+    fn crate_info_command_extra_args() {
+        let command = crate_info_command(
+            Path::new("bazel"),
+            Path::new("/workspace"),
+            "@rules_rust//rust:defs.bzl%rust_analyzer_aspect",
+            &["//foo/...".to_owned(), "//bar:baz".to_owned()],
+            None,
+            &[
+                "--config=rust_analyzer".to_owned(),
+                "--//my:flag".to_owned(),
+            ],
+            &["rust_analyzer_crate_spec".to_owned()],
+            None,
+        );
+
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            args[args.len() - 4..],
+            [
+                "--config=rust_analyzer",
+                "--//my:flag",
+                "//foo/...",
+                "//bar:baz"
+            ]
+        );
+        assert_eq!(args[0], "build");
+    }
+
+    #[test]
+    fn crate_info_command_custom_aspect() {
+        let aspect = rust_analyzer_aspect(
+            "@rules_rust",
+            Some("@my_rules_rust//rust/private:rust_analyzer.bzl%rust_analyzer_aspect"),
+        )
+        .unwrap();
+        let command = crate_info_command(
+            Path::new("bazel"),
+            Path::new("/workspace"),
+            &aspect,
+            &["//foo/...".to_owned()],
+            None,
+            &[],
+            &["rust_analyzer_crate_spec".to_owned()],
+            None,
+        );
+
+        assert!(command.get_args().any(|arg| arg
+            == "--aspects=@my_rules_rust//rust/private:rust_analyzer.bzl%rust_analyzer_aspect"));
+    }
+
+    #[test]
+    fn crate_info_command_output_groups() {
+        let output_groups: Vec<String> = DEFAULT_OUTPUT_GROUPS
+            .iter()
+            .map(|&group| group.to_owned())
+            .chain(["rust_analyzer_proc_macro".to_owned()])
+            .collect();
+        let command = crate_info_command(
+            Path::new("bazel"),
+            Path::new("/workspace"),
+            "@rules_rust//rust:defs.bzl%rust_analyzer_aspect",
+            &["//foo/...".to_owned()],
+            None,
+            &[],
+            &output_groups,
+            Some(Path::new("/tmp/build_events.json")),
+        );
+
+        assert!(command.get_args().any(|arg| arg
+            == "--output_groups=rust_analyzer_crate_spec,rust_generated_srcs,rust_analyzer_proc_macro_dylib,rust_analyzer_proc_macro"));
+        assert!(command
+            .get_args()
+            .any(|arg| arg == "--build_event_json_file=/tmp/build_events.json"));
+    }
+
+    #[test]
+    fn rust_analyzer_aspect_validation() {
+        assert_eq!(
+            rust_analyzer_aspect("@rules_rust", None).unwrap(),
+            "@rules_rust//rust:defs.bzl%rust_analyzer_aspect"
+        );
+        assert_eq!(
+            rust_analyzer_aspect("@rules_rust", Some("//tools:aspects.bzl%my_aspect")).unwrap(),
+            "//tools:aspects.bzl%my_aspect"
+        );
+        for invalid in [
+            "rust_analyzer_aspect",
+            "//tools:aspects.bzl",
+            "//tools:aspects.bzl%",
+            "//tools:aspects%my_aspect",
+            "//tools:aspects.bzl%my-aspect",
+        ] {
+            let err = rust_analyzer_aspect("@rules_rust", Some(invalid)).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("Invalid aspect `{invalid}`")),
+                "{}",
+                err
+            );
+        }
+    }
 }
@@ -1,18 +1,31 @@
-use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::path::PathBuf;
-use std::process::Command;
 
 use anyhow::anyhow;
 use clap::Parser;
+use gen_rust_project_lib::bazel_output_paths;
 use gen_rust_project_lib::generate_crate_info;
+use gen_rust_project_lib::generate_crate_info_files;
+use gen_rust_project_lib::print_rust_project;
+use gen_rust_project_lib::read_targets;
 use gen_rust_project_lib::write_rust_project;
+use gen_rust_project_lib::DEFAULT_OUTPUT_GROUPS;
+use gen_rust_project_lib::{
+    ConsolidationKey, ConsolidationOptions, CrateOrdering, RustProjectOptions,
+};
+use gen_rust_project_lib::{JsonLogger, LOG_FORMAT_ENV_VAR};
 
 // TODO(david): This shells out to an expected rule in the workspace root //:rust_analyzer that the user must define.
 // It would be more convenient if it could automatically discover all the rust code in the workspace if this target
 // does not exist.
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    if env::var(LOG_FORMAT_ENV_VAR).is_ok_and(|format| format == "json") {
+        JsonLogger::init()?;
+    } else {
+        env_logger::init();
+    }
 
     let config = parse_config()?;
 
@@ -33,24 +46,111 @@ fn main() -> anyhow::Result<()> {
 
     let rules_rust_name = env!("ASPECT_REPOSITORY");
 
-    // Generate the crate specs.
-    generate_crate_info(
-        &config.bazel,
-        workspace_root,
-        rules_rust_name,
-        &config.targets,
-    )?;
+    let mut options = RustProjectOptions {
+        target_filter: config.target_filter.clone(),
+        discover_sysroot: config.discover_sysroot,
+        crate_ordering: if config.group_crates_by_membership {
+            CrateOrdering::GroupedByMembership
+        } else {
+            CrateOrdering::Resolution
+        },
+        post_generation_hook: config.post_generation_hook.clone(),
+        synthesize_manifest_dir: config.synthesize_manifest_dir,
+        omit_non_workspace_members: config.omit_non_workspace_members,
+        consolidation: ConsolidationOptions {
+            strict: config.strict_consolidation,
+            key: if config.consolidate_by_root_module {
+                ConsolidationKey::RootModuleAndDisplayName
+            } else {
+                ConsolidationKey::CrateId
+            },
+        },
+        crate_labels_path: config.crate_labels.clone(),
+        compilation_mode: config.compilation_mode.clone(),
+        resolve_symlinks: config.resolve_symlinks,
+        preserve_sysroot: config.preserve_sysroot,
+        prune_orphans: config.prune_orphans,
+        cache_crate_specs: config.cache_crate_specs || config.refresh_crate_spec_cache,
+        refresh_crate_spec_cache: config.refresh_crate_spec_cache,
+        bazel_args: config.bazel_args.clone(),
+        aspect: config.aspect.clone(),
+        min_edition: config.min_edition.clone(),
+        crate_spec_dir: config.crate_spec_dir.clone(),
+        toolchain_info: config.toolchain_info.clone(),
+        require_sysroot: config.require_sysroot,
+        non_member_prefixes: config.non_member_prefixes.clone(),
+        emit_crate_ids: config.emit_crate_ids,
+        emit_crate_types: config.emit_crate_types,
+        strict_root_modules: config.strict_root_modules,
+        no_deps: config.no_deps,
+        crate_spec_files: None,
+        include_labels: config.include_labels.clone(),
+        exclude_labels: config.exclude_labels.clone(),
+        extra_cfgs: config.extra_cfgs.clone(),
+        break_cycles: config.break_cycles,
+    };
 
-    // Use the generated files to write rust-project.json.
-    write_rust_project(
-        &config.bazel,
-        workspace_root,
-        &rules_rust_name,
-        &config.targets,
-        execution_root,
-        output_base,
-        workspace_root.join("rust-project.json"),
-    )?;
+    // Generate the crate specs, unless they were generated by an earlier build.
+    if config.crate_spec_dir.is_none() {
+        let output_groups: Vec<String> = DEFAULT_OUTPUT_GROUPS
+            .iter()
+            .map(|&group| group.to_owned())
+            .chain(config.output_groups.iter().cloned())
+            .collect();
+        if config.crate_specs_from_build_events {
+            options.crate_spec_files = Some(generate_crate_info_files(
+                &config.bazel,
+                workspace_root,
+                rules_rust_name,
+                config.aspect.as_deref(),
+                &config.targets,
+                config.compilation_mode.as_deref(),
+                &config.bazel_args,
+                &output_groups,
+            )?);
+        } else {
+            generate_crate_info(
+                &config.bazel,
+                workspace_root,
+                rules_rust_name,
+                config.aspect.as_deref(),
+                &config.targets,
+                config.compilation_mode.as_deref(),
+                &config.bazel_args,
+                &output_groups,
+            )?;
+        }
+    }
+
+    let summary = if config.stdout {
+        // Use the generated files to print the rust-project.json content.
+        print_rust_project(
+            &config.bazel,
+            workspace_root,
+            &rules_rust_name,
+            &config.targets,
+            execution_root,
+            output_base,
+            &options,
+        )?
+    } else {
+        // Use the generated files to write rust-project.json.
+        write_rust_project(
+            &config.bazel,
+            workspace_root,
+            &rules_rust_name,
+            &config.targets,
+            execution_root,
+            output_base,
+            workspace_root.join("rust-project.json"),
+            &options,
+        )?
+    };
+
+    eprintln!("{summary}");
+    if summary.unchanged {
+        eprintln!("rust-project.json is unchanged.");
+    }
 
     Ok(())
 }
@@ -59,49 +159,23 @@ fn main() -> anyhow::Result<()> {
 fn parse_config() -> anyhow::Result<Config> {
     let mut config = Config::parse();
 
-    if config.workspace.is_some() && config.execution_root.is_some() {
-        return Ok(config);
-    }
-
-    // We need some info from `bazel info`. Fetch it now.
-    let mut bazel_info_command = Command::new(&config.bazel);
-    bazel_info_command
-        .env_remove("BAZELISK_SKIP_WRAPPER")
-        .env_remove("BUILD_WORKING_DIRECTORY")
-        .env_remove("BUILD_WORKSPACE_DIRECTORY")
-        .arg("info");
-    if let Some(workspace) = &config.workspace {
-        bazel_info_command.current_dir(workspace);
-    }
-
-    // Execute bazel info.
-    let output = bazel_info_command.output()?;
-    if !output.status.success() {
-        return Err(anyhow!(
-            "Failed to run `bazel info` ({:?}): {}",
-            output.status,
-            String::from_utf8_lossy(&output.stderr)
-        ));
+    if config.targets_from_stdin {
+        config.targets = read_targets(io::stdin().lock())?;
+    } else if let Some(targets_file) = &config.targets_file {
+        let file = File::open(targets_file)
+            .map_err(|e| anyhow!("Failed to open {}: {e}", targets_file.display()))?;
+        config.targets = read_targets(BufReader::new(file))?;
     }
 
-    // Extract the output.
-    let output = String::from_utf8_lossy(output.stdout.as_slice());
-    let bazel_info = output
-        .trim()
-        .split('\n')
-        .map(|line| line.split_at(line.find(':').expect("missing `:` in bazel info output")))
-        .map(|(k, v)| (k, (v[1..]).trim()))
-        .collect::<HashMap<_, _>>();
-
-    if config.workspace.is_none() {
-        config.workspace = bazel_info.get("workspace").map(Into::into);
-    }
-    if config.execution_root.is_none() {
-        config.execution_root = bazel_info.get("execution_root").map(Into::into);
-    }
-    if config.output_base.is_none() {
-        config.output_base = bazel_info.get("output_base").map(Into::into);
-    }
+    let (workspace, execution_root, output_base) = bazel_output_paths(
+        &config.bazel,
+        config.workspace.take(),
+        config.execution_root.take(),
+        config.output_base.take(),
+    )?;
+    config.workspace = Some(workspace);
+    config.execution_root = Some(execution_root);
+    config.output_base = Some(output_base);
 
     Ok(config)
 }
@@ -124,6 +198,159 @@ struct Config {
     #[clap(long, default_value = "bazel")]
     bazel: PathBuf,
 
+    /// Print the `rust-project.json` content to stdout instead of writing it to the workspace.
+    #[clap(long)]
+    stdout: bool,
+
+    /// Only emit crates built for this target triple (and the crates they depend on).
+    #[clap(long)]
+    target_filter: Option<String>,
+
+    /// Omit the sysroot from `rust-project.json` so rust-analyzer discovers it from the `rustc` on `PATH`.
+    #[clap(long)]
+    discover_sysroot: bool,
+
+    /// List workspace member crates first, then external crates, each sorted by name.
+    #[clap(long)]
+    group_crates_by_membership: bool,
+
+    /// A program to run with the path of the written `rust-project.json` after generation.
+    /// Skipped when `rust-project.json` is unchanged.
+    #[clap(long, env = "RUST_PROJECT_POST_GENERATION_HOOK")]
+    post_generation_hook: Option<PathBuf>,
+
+    /// Set `CARGO_MANIFEST_DIR` to each crate's package directory when the crate doesn't define it.
+    #[clap(long)]
+    synthesize_manifest_dir: bool,
+
+    /// Omit `is_workspace_member` for external crates instead of emitting `false`.
+    #[clap(long)]
+    omit_non_workspace_members: bool,
+
+    /// Fail when crate specs sharing a crate_id and target disagree on edition instead of warning.
+    #[clap(long)]
+    strict_consolidation: bool,
+
+    /// Merge crate specs sharing a root module and display name even if their crate_ids differ.
+    /// This can over-merge unrelated crates that happen to share both.
+    #[clap(long)]
+    consolidate_by_root_module: bool,
+
+    /// Also write a JSON file to this path mapping each crate in `rust-project.json` to its Bazel target.
+    #[clap(long)]
+    crate_labels: Option<PathBuf>,
+
+    /// The Bazel `--compilation_mode` to build and query crate specs with (e.g. `dbg` or `opt`).
+    #[clap(long)]
+    compilation_mode: Option<String>,
+
+    /// Resolve symlinked root modules to their real paths, merging crates that share one.
+    #[clap(long)]
+    resolve_symlinks: bool,
+
+    /// Keep the `sysroot` and `sysroot_src` of an existing `rust-project.json` instead of overwriting them.
+    #[clap(long)]
+    preserve_sysroot: bool,
+
+    /// Leave out crates that no workspace member depends on, directly or transitively.
+    #[clap(long)]
+    prune_orphans: bool,
+
+    /// Cache crate specs under the output base and reuse them while no BUILD, .bzl, MODULE or lock file changes.
+    #[clap(long)]
+    cache_crate_specs: bool,
+
+    /// Ignore and overwrite the crate spec cache. Implies `--cache-crate-specs`.
+    #[clap(long)]
+    refresh_crate_spec_cache: bool,
+
+    /// An extra flag to pass to `bazel build` and `bazel aquery`, e.g. `--bazel-arg=--config=rust_analyzer`.
+    /// May be repeated.
+    #[clap(long = "bazel-arg", allow_hyphen_values = true)]
+    bazel_args: Vec<String>,
+
+    /// An output group to build along with the crate specs, e.g. one produced by a custom
+    /// aspect. May be repeated.
+    #[clap(long = "output-group")]
+    output_groups: Vec<String>,
+
+    /// The aspect generating crate specs, for forks of rules_rust that define it elsewhere,
+    /// e.g. `@my_rules_rust//rust/private:rust_analyzer.bzl%rust_analyzer_aspect`.
+    #[clap(long)]
+    aspect: Option<String>,
+
+    /// Analyze crates with an older edition as this edition (2015, 2018, 2021 or 2024).
+    #[clap(long)]
+    min_edition: Option<String>,
+
+    /// Read crate specs from the `*.rust_analyzer_crate_spec.json` files under this directory
+    /// instead of running `bazel build` and `bazel aquery`.
+    #[clap(long)]
+    crate_spec_dir: Option<PathBuf>,
+
+    /// Read the crate specs listed in the build's event stream instead of running `bazel aquery`
+    /// to find them.
+    #[clap(long, conflicts_with = "crate_spec_dir")]
+    crate_specs_from_build_events: bool,
+
+    /// The `rust_analyzer_toolchain.json` file to read the sysroot from, instead of the one
+    /// generated by `@rules_rust//rust/private:rust_analyzer_detect_sysroot`.
+    #[clap(long)]
+    toolchain_info: Option<PathBuf>,
+
+    /// Fail if the sysroot or its sources don't exist instead of warning.
+    #[clap(long)]
+    require_sysroot: bool,
+
+    /// Treat crates whose Bazel target starts with this prefix, e.g. `@crates//`, as external
+    /// even if their crate spec marks them as workspace members. May be repeated.
+    #[clap(long = "non-member-prefix")]
+    non_member_prefixes: Vec<String>,
+
+    /// Emit each crate's `crate_id` in `rust-project.json` for tools correlating crates with Bazel.
+    #[clap(long)]
+    emit_crate_ids: bool,
+
+    /// Emit each crate's `crate_type` (e.g. `cdylib`) in `rust-project.json` for tools post-processing it.
+    #[clap(long)]
+    emit_crate_types: bool,
+
+    /// Fail when distinct targets share a root module instead of warning.
+    #[clap(long)]
+    strict_root_modules: bool,
+
+    /// Only generate crates for the given targets, not their dependencies. Faster, but
+    /// dependencies on other crates won't resolve.
+    #[clap(long)]
+    no_deps: bool,
+
+    /// Only emit crates whose target matches this label pattern, e.g. `//foo/...`. May be repeated.
+    #[clap(long = "include-label")]
+    include_labels: Vec<String>,
+
+    /// Leave out crates whose target matches this label pattern, e.g. `//third_party/...`.
+    /// Dependencies on them are omitted. May be repeated.
+    #[clap(long = "exclude-label")]
+    exclude_labels: Vec<String>,
+
+    /// A cfg to enable on every crate, e.g. `--cfg=tokio_unstable`. May be repeated.
+    #[clap(long = "cfg")]
+    extra_cfgs: Vec<String>,
+
+    /// Drop dependencies that close a cycle in the crate graph instead of failing.
+    #[clap(long)]
+    break_cycles: bool,
+
+    /// Read newline-delimited target patterns from stdin instead of the command line.
+    /// Blank lines and lines starting with `#` are ignored.
+    #[clap(long, conflicts_with_all = ["targets_file", "targets"])]
+    targets_from_stdin: bool,
+
+    /// Read newline-delimited target patterns from this file instead of the command line.
+    /// Blank lines and lines starting with `#` are ignored.
+    #[clap(long, conflicts_with = "targets")]
+    targets_file: Option<PathBuf>,
+
     /// Space separated list of target patterns that comes after all other args.
     #[clap(default_value = "@//...")]
     targets: Vec<String>,
@@ -2,13 +2,14 @@
 //! See official documentation of file format at https://rust-analyzer.github.io/manual.html
 
 use std::collections::{BTreeMap, BTreeSet, HashMap};
-use std::io::ErrorKind;
-use std::path::Path;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::anyhow;
 use serde::Serialize;
 
-use crate::aquery::CrateSpec;
+use crate::aquery::{ConsolidationOptions, CrateSpec};
 
 /// A `rust-project.json` workspace representation. See
 /// [rust-analyzer documentation][rd] for a thorough description of this interface.
@@ -16,10 +17,12 @@ use crate::aquery::CrateSpec;
 #[derive(Debug, Serialize)]
 pub struct RustProject {
     /// The path to a Rust sysroot.
+    #[serde(skip_serializing_if = "Option::is_none")]
     sysroot: Option<String>,
 
     /// Path to the directory with *source code* of
     /// sysroot crates.
+    #[serde(skip_serializing_if = "Option::is_none")]
     sysroot_src: Option<String>,
 
     /// The set of crates comprising the current
@@ -27,6 +30,16 @@ pub struct RustProject {
     /// dependencies as well as sysroot crate (libstd,
     /// libcore and such).
     crates: Vec<Crate>,
+
+    /// The root of the Bazel workspace, which rust-analyzer can anchor relative
+    /// operations such as reloading the workspace at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workspace: Option<String>,
+
+    /// The number of crates left out because they aren't reachable from any
+    /// workspace member. This is not part of the `rust-project.json` format.
+    #[serde(skip)]
+    pruned_crates: usize,
 }
 
 /// A `rust-project.json` crate representation. See
@@ -60,6 +73,12 @@ pub struct Crate {
     /// `["unix", "feature=\"foo\"", "feature=\"bar\""]`.
     cfg: Vec<String>,
 
+    /// Additional rustc flags the crate is compiled with, e.g. `-Z` flags that
+    /// affect cfg evaluation. rust-analyzer ignores this key; it is carried for
+    /// other tools reading `rust-project.json`. `--cfg` flags also appear in `cfg`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rustc_flags: Vec<String>,
+
     /// Target triple for this Crate.
     #[serde(skip_serializing_if = "Option::is_none")]
     target: Option<String>,
@@ -74,6 +93,28 @@ pub struct Crate {
     /// For proc-macro crates, path to compiled proc-macro (.so file).
     #[serde(skip_serializing_if = "Option::is_none")]
     proc_macro_dylib_path: Option<String>,
+
+    /// For proc-macro crates, the working directory the proc-macro server expands
+    /// the crate's macros in: the crate's package directory, matching Cargo.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proc_macro_cwd: Option<String>,
+
+    /// The `crate_id` of the crate spec this crate was generated from, for tools
+    /// correlating crates with Bazel. This is not part of the `rust-project.json`
+    /// format, and rust-analyzer ignores it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crate_id: Option<String>,
+
+    /// The crate type from the crate spec, e.g. `rlib` or `cdylib`, for tools
+    /// post-processing the project. This is not part of the `rust-project.json`
+    /// format, and rust-analyzer ignores it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crate_type: Option<String>,
+
+    /// The Bazel label of the target that produced this crate. This is not part
+    /// of the `rust-project.json` format.
+    #[serde(skip)]
+    bazel_target: String,
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -99,26 +140,230 @@ pub struct Dependency {
     name: String,
 }
 
+/// Options controlling how a [RustProject] is generated from a set of crate specs
+/// and written to disk.
+#[derive(Clone, Debug, Default)]
+pub struct RustProjectOptions {
+    /// When set, only crates built for this target triple (and the crates they
+    /// depend on) are emitted. Crates without a target are always kept.
+    pub target_filter: Option<String>,
+
+    /// Omit `sysroot` and `sysroot_src` so rust-analyzer discovers the sysroot
+    /// itself (e.g. from the `rustc` on `PATH`) instead of using the one from the
+    /// Bazel toolchain. Useful when the toolchain's standard library sources are
+    /// unavailable.
+    pub discover_sysroot: bool,
+
+    /// The order in which crates are listed in the generated project.
+    pub crate_ordering: CrateOrdering,
+
+    /// A program to run after `rust-project.json` is written, with the path of the
+    /// written file as its only argument. Generation fails if it exits unsuccessfully.
+    /// It isn't run when `rust-project.json` is already up to date.
+    pub post_generation_hook: Option<PathBuf>,
+
+    /// Set `CARGO_MANIFEST_DIR` to the crate's package directory when the crate
+    /// spec doesn't provide one, so manifest-relative paths (e.g. `include_str!`)
+    /// resolve in the editor the same way they do in the build.
+    pub synthesize_manifest_dir: bool,
+
+    /// Omit `is_workspace_member` for crates outside the workspace instead of
+    /// emitting `false`, leaving rust-analyzer to infer it.
+    pub omit_non_workspace_members: bool,
+
+    /// Options for consolidating crate specs that share a `crate_id`.
+    pub consolidation: ConsolidationOptions,
+
+    /// When set, a JSON file mapping each emitted crate to the Bazel target that
+    /// produced it is written to this path alongside `rust-project.json`.
+    pub crate_labels_path: Option<PathBuf>,
+
+    /// The `--compilation_mode` crate specs are built and queried with. A
+    /// toolchain info file specific to this mode is used for the sysroot when present.
+    pub compilation_mode: Option<String>,
+
+    /// Replace `root_module` and `source.include_dirs` with their real paths,
+    /// merging crates whose root modules are symlinks to the same file so
+    /// rust-analyzer doesn't index them twice.
+    pub resolve_symlinks: bool,
+
+    /// Keep the `sysroot` and `sysroot_src` of an existing `rust-project.json`
+    /// when regenerating it, so hand-pinned values aren't overwritten. Fields
+    /// missing from the existing file are filled from the toolchain as usual.
+    pub preserve_sysroot: bool,
+
+    /// Leave out crates that no workspace member (transitively) depends on, e.g.
+    /// leftovers from an over-broad `deps()` query.
+    pub prune_orphans: bool,
+
+    /// Cache the crate specs under the output base, keyed by the targets and the
+    /// modification times of the workspace's build files (BUILD, `.bzl`, MODULE and
+    /// lock files), and skip the aquery when the cache is current.
+    pub cache_crate_specs: bool,
+
+    /// Ignore and overwrite any cached crate specs.
+    pub refresh_crate_spec_cache: bool,
+
+    /// Extra flags passed to `bazel aquery` when querying crate specs, e.g.
+    /// `--config=rust_analyzer`. They should match those used to build the specs.
+    pub bazel_args: Vec<String>,
+
+    /// The aspect generating the crate specs, in `label%symbol` form. Defaults to the
+    /// `rust_analyzer_aspect` of the rules_rust repository, and can be set for forks
+    /// that define it elsewhere.
+    pub aspect: Option<String>,
+
+    /// Analyze crates with an older edition as this one instead, e.g. `2021`. The
+    /// crates' BUILD files are left unchanged.
+    pub min_edition: Option<String>,
+
+    /// Read the crate specs from the spec files under this directory instead of
+    /// querying Bazel for them, e.g. where only the output of an earlier build is
+    /// available.
+    pub crate_spec_dir: Option<PathBuf>,
+
+    /// Read the sysroot from this toolchain info file instead of the one in the
+    /// runfiles of the rules_rust repository.
+    pub toolchain_info: Option<PathBuf>,
+
+    /// Fail instead of warning when the sysroot or its sources don't exist.
+    pub require_sysroot: bool,
+
+    /// Crates whose Bazel target starts with one of these prefixes, e.g. `@crates//`,
+    /// are never workspace members, whatever their crate spec says.
+    pub non_member_prefixes: Vec<String>,
+
+    /// Emit each crate's `crate_id` so tools post-processing `rust-project.json`
+    /// can correlate crates with their crate specs.
+    pub emit_crate_ids: bool,
+
+    /// Emit each crate's `crate_type` (e.g. `cdylib` or `staticlib`) for tools
+    /// post-processing `rust-project.json`.
+    pub emit_crate_types: bool,
+
+    /// Fail instead of warning when distinct Bazel targets share a root module.
+    pub strict_root_modules: bool,
+
+    /// Query only the requested targets rather than their transitive dependencies,
+    /// for quick iteration on a single crate. Dependencies on crates that weren't
+    /// queried are dropped.
+    pub no_deps: bool,
+
+    /// Read these crate spec files, e.g. as reported by the build generating them,
+    /// instead of querying Bazel for them. Relative paths are relative to the
+    /// execution root.
+    pub crate_spec_files: Option<Vec<PathBuf>>,
+
+    /// When not empty, only crates whose Bazel target matches one of these label
+    /// patterns (e.g. `//foo/...`, `//foo:all` or `//foo:bar`) are emitted.
+    pub include_labels: Vec<String>,
+
+    /// Crates whose Bazel target matches one of these label patterns are left out.
+    pub exclude_labels: Vec<String>,
+
+    /// cfgs added to every crate, e.g. `tokio_unstable`, as if passed to every rustc
+    /// invocation with `--cfg`.
+    pub extra_cfgs: Vec<String>,
+
+    /// When the dependency graph has a cycle, drop the dependency closing it (with a
+    /// warning) and carry on, instead of failing. A slightly wrong project is more
+    /// useful to rust-analyzer than none.
+    pub break_cycles: bool,
+}
+
+/// The order in which crates are emitted in a [RustProject].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CrateOrdering {
+    /// Crates are listed in the order their dependencies were resolved, with crates
+    /// resolved in the same pass ordered by crate_id.
+    #[default]
+    Resolution,
+
+    /// Workspace members are listed first, followed by all other crates. Each
+    /// group is sorted by display name.
+    GroupedByMembership,
+}
+
 pub fn generate_rust_project(
     sysroot: &str,
     sysroot_src: &str,
+    workspace: &str,
     crates: &BTreeSet<CrateSpec>,
+    options: &RustProjectOptions,
 ) -> anyhow::Result<RustProject> {
-    let mut project = RustProject {
-        sysroot: Some(sysroot.into()),
-        sysroot_src: Some(sysroot_src.into()),
-        crates: Vec::new(),
+    if let Some(min_edition) = &options.min_edition {
+        if !EDITIONS.contains(&min_edition.as_str()) {
+            return Err(anyhow!(
+                "Unsupported minimum edition `{min_edition}`, expected one of {}",
+                EDITIONS.join(", ")
+            ));
+        }
+    }
+
+    let mut project = if options.discover_sysroot {
+        RustProject {
+            sysroot: None,
+            sysroot_src: None,
+            crates: Vec::new(),
+            workspace: None,
+            pruned_crates: 0,
+        }
+    } else {
+        sysroot_only_project(sysroot, sysroot_src)
     };
 
-    let mut unmerged_crates: Vec<&CrateSpec> = crates.iter().collect();
+    if !workspace.is_empty() {
+        project.workspace = Some(workspace.to_owned());
+    }
+
+    let mut unmerged_crates: Vec<&CrateSpec> = match &options.target_filter {
+        Some(target) => filter_by_target(crates, target),
+        None => crates.iter().collect(),
+    };
+    // Crates left out by label are dropped from the deps of their dependents.
+    let mut excluded_crate_ids: BTreeSet<&str> = BTreeSet::new();
+    unmerged_crates.retain(|c| {
+        let selected = is_selected_by_label(&c.bazel_target, options);
+        if !selected {
+            log::debug!("Excluding crate {} by label", c.bazel_target);
+            excluded_crate_ids.insert(c.crate_id.as_str());
+        }
+        selected
+    });
+    if options.prune_orphans {
+        let total = unmerged_crates.len();
+        unmerged_crates = prune_orphans(unmerged_crates, options);
+        project.pruned_crates = total - unmerged_crates.len();
+    }
+    // Resolve crates in crate_id order so the output, and with it every dependency's
+    // crate_index, doesn't depend on how the specs happen to sort.
+    unmerged_crates.sort_by(|a, b| a.crate_id.cmp(&b.crate_id));
+    // Without the dependency closure, deps on crates outside the queried targets can
+    // never be resolved, so ignore them rather than failing to make progress.
+    let known_crate_ids: BTreeSet<&str> = unmerged_crates
+        .iter()
+        .map(|c| c.crate_id.as_str())
+        .collect();
+    let resolvable = |dep: &&String| {
+        !excluded_crate_ids.contains(dep.as_str())
+            && (!options.no_deps || known_crate_ids.contains(dep.as_str()))
+    };
+    // Dependencies dropped to break cycles, as (crate_id, dep) pairs.
+    let mut dropped_edges: BTreeSet<(String, String)> = BTreeSet::new();
     let mut skipped_crates: Vec<&CrateSpec> = Vec::new();
     let mut merged_crates_index: HashMap<String, usize> = HashMap::new();
 
     while !unmerged_crates.is_empty() {
         for c in unmerged_crates.iter() {
-            if c.deps
+            let deps: Vec<&String> = c
+                .deps
+                .iter()
+                .filter(resolvable)
+                .filter(|dep| !dropped_edges.contains(&(c.crate_id.clone(), (*dep).clone())))
+                .collect();
+            if deps
                 .iter()
-                .any(|dep| !merged_crates_index.contains_key(dep))
+                .any(|dep| !merged_crates_index.contains_key(*dep))
             {
                 log::trace!(
                     "Skipped crate {} because missing deps: {:?}",
@@ -132,44 +377,69 @@ pub fn generate_rust_project(
                 skipped_crates.push(c);
             } else {
                 log::trace!("Merging crate {}", &c.crate_id);
+                for dep in c.deps.iter().filter(|dep| !resolvable(dep)) {
+                    log::debug!(
+                        "Dropping dependency of {} on {}, which isn't in the project",
+                        c.crate_id,
+                        dep
+                    );
+                }
                 merged_crates_index.insert(c.crate_id.clone(), project.crates.len());
                 project.crates.push(Crate {
                     display_name: Some(c.display_name.clone()),
+                    bazel_target: c.bazel_target.clone(),
                     root_module: c.root_module.clone(),
-                    edition: c.edition.clone(),
-                    deps: c
-                        .deps
-                        .iter()
-                        .map(|dep| {
-                            let crate_index = *merged_crates_index
-                                .get(dep)
-                                .expect("failed to find dependency on second lookup");
-                            let dep_crate = &project.crates[crate_index];
-                            let name = if let Some(alias) = c.aliases.get(dep) {
-                                alias.clone()
-                            } else {
-                                dep_crate
-                                    .display_name
-                                    .as_ref()
-                                    .expect("all crates should have display_name")
-                                    .clone()
-                            };
-                            Dependency { crate_index, name }
-                        })
-                        .collect(),
-                    is_workspace_member: Some(c.is_workspace_member),
+                    edition: match &options.min_edition {
+                        Some(min_edition) if c.edition.as_str() < min_edition.as_str() => {
+                            min_edition.clone()
+                        }
+                        _ => c.edition.clone(),
+                    },
+                    deps: unique_dependency_names(
+                        &c.crate_id,
+                        deps.into_iter()
+                            .map(|dep| {
+                                let crate_index = *merged_crates_index
+                                    .get(dep)
+                                    .expect("failed to find dependency on second lookup");
+                                let dep_crate = &project.crates[crate_index];
+                                let (name, aliased) = match c.aliases.get(dep) {
+                                    Some(alias) => (alias.clone(), true),
+                                    None => (dependency_name(dep, dep_crate), false),
+                                };
+                                (Dependency { crate_index, name }, aliased)
+                            })
+                            .collect(),
+                    ),
+                    is_workspace_member: Some(is_workspace_member(c, options))
+                        .filter(|&member| member || !options.omit_non_workspace_members),
                     source: match &c.source {
                         Some(s) => Source {
-                            exclude_dirs: s.exclude_dirs.clone(),
-                            include_dirs: s.include_dirs.clone(),
+                            exclude_dirs: s
+                                .exclude_dirs
+                                .iter()
+                                .map(|d| anchor_source_dir(d))
+                                .collect(),
+                            include_dirs: s
+                                .include_dirs
+                                .iter()
+                                .map(|d| anchor_source_dir(d))
+                                .collect(),
                         },
                         None => Source::default(),
                     },
-                    cfg: c.cfg.clone(),
+                    cfg: c.cfg.iter().chain(&options.extra_cfgs).cloned().collect(),
+                    rustc_flags: c.rustc_flags.clone(),
                     target: Some(c.target.clone()),
-                    env: Some(c.env.clone()),
+                    env: Some(crate_env(c, options)),
                     is_proc_macro: c.proc_macro_dylib_path.is_some(),
                     proc_macro_dylib_path: c.proc_macro_dylib_path.clone(),
+                    proc_macro_cwd: c
+                        .proc_macro_dylib_path
+                        .as_ref()
+                        .and_then(|_| manifest_dir(&c.bazel_target)),
+                    crate_id: options.emit_crate_ids.then(|| c.crate_id.clone()),
+                    crate_type: options.emit_crate_types.then(|| c.crate_type.clone()),
                 });
             }
         }
@@ -186,32 +456,750 @@ pub fn generate_rust_project(
                 .map(|c| (c.crate_id.to_string(), *c))
                 .collect();
 
-            for unmerged_crate in &unmerged_crates {
+            // Walk the crates in crate_id order so the reported cycle is deterministic.
+            let mut first_cycle: Option<Vec<String>> = None;
+            for unmerged_crate in crate_map.values() {
                 let mut path = vec![];
-                if let Some(cycle) = detect_cycle(unmerged_crate, &crate_map, &mut path) {
+                if let Some(cycle) =
+                    detect_cycle(unmerged_crate, &crate_map, &dropped_edges, &mut path)
+                {
+                    let cycle: Vec<String> = cycle.iter().map(|c| c.crate_id.to_string()).collect();
+                    log::warn!("Cycle detected: {:?}", cycle);
+                    first_cycle.get_or_insert(cycle);
+                }
+            }
+            if let Some([.., dependent, dep]) = first_cycle.as_deref() {
+                if options.break_cycles {
                     log::warn!(
-                        "Cycle detected: {:?}",
-                        cycle
-                            .iter()
-                            .map(|c| c.crate_id.to_string())
-                            .collect::<Vec<String>>()
+                        "Breaking dependency cycle by dropping the dependency of {} on {}",
+                        dependent,
+                        dep
                     );
+                    dropped_edges.insert((dependent.clone(), dep.clone()));
+                    skipped_crates.clear();
+                    continue;
                 }
             }
-            return Err(anyhow!(
-                "Failed to make progress on building crate dependency graph"
-            ));
+            return Err(match first_cycle {
+                Some(cycle) => anyhow!(
+                    "Failed to make progress on building crate dependency graph: cycle detected: {}",
+                    cycle.join(" -> ")
+                ),
+                None => anyhow!("Failed to make progress on building crate dependency graph"),
+            });
         }
         std::mem::swap(&mut unmerged_crates, &mut skipped_crates);
         skipped_crates.clear();
     }
 
+    canonicalize_rust_project(&mut project, options);
+    check_duplicate_root_modules(&project, options.strict_root_modules)?;
+
     Ok(project)
 }
 
+/// Returns whether the crate built by `bazel_target` is kept by the
+/// [RustProjectOptions::include_labels] and [RustProjectOptions::exclude_labels].
+/// Crates without a label, from specs written by older aspects, are always kept.
+fn is_selected_by_label(bazel_target: &str, options: &RustProjectOptions) -> bool {
+    if bazel_target.is_empty() {
+        return true;
+    }
+    let matches = |pattern: &String| matches_label_pattern(pattern, bazel_target);
+    (options.include_labels.is_empty() || options.include_labels.iter().any(matches))
+        && !options.exclude_labels.iter().any(matches)
+}
+
+/// Returns whether `label` matches `pattern`, which is a label, a package's targets
+/// (`//pkg:all` or `//pkg:*`) or a package and its subpackages (`//pkg/...`).
+fn matches_label_pattern(pattern: &str, label: &str) -> bool {
+    // `@//pkg` and `@@//pkg` are the main repository, like `//pkg`.
+    fn normalize(label: &str) -> &str {
+        let label = label.strip_prefix("@@").unwrap_or(label);
+        label
+            .strip_prefix('@')
+            .filter(|l| l.starts_with("//"))
+            .unwrap_or(label)
+    }
+    let pattern = normalize(pattern);
+    let label = normalize(label);
+    let package = label.split_once(':').map_or(label, |(package, _)| package);
+
+    if let Some(prefix) = pattern.strip_suffix("/...") {
+        return package == prefix || package.starts_with(&format!("{prefix}/"));
+    }
+    if let Some(pattern_package) = pattern
+        .strip_suffix(":all")
+        .or_else(|| pattern.strip_suffix(":*"))
+    {
+        return package == pattern_package;
+    }
+    match pattern.split_once(':') {
+        Some(_) => pattern == label,
+        // `//pkg/name` is short for `//pkg/name:name`.
+        None => {
+            let name = pattern.rsplit('/').next().unwrap_or_default();
+            label.strip_prefix(pattern) == Some(&format!(":{name}"))
+        }
+    }
+}
+
+/// Returns whether `spec` is a workspace member, unless its target matches one of
+/// [RustProjectOptions::non_member_prefixes].
+fn is_workspace_member(spec: &CrateSpec, options: &RustProjectOptions) -> bool {
+    spec.is_workspace_member
+        && !options
+            .non_member_prefixes
+            .iter()
+            .any(|prefix| spec.bazel_target.starts_with(prefix.as_str()))
+}
+
+/// Returns a project with only the sysroot, which is enough for rust-analyzer to
+/// resolve `std` and `core` in files outside any crate (e.g. a scratch file) while
+/// the real project is generated.
+pub fn sysroot_only_project(sysroot: &str, sysroot_src: &str) -> RustProject {
+    RustProject {
+        sysroot: Some(exec_root_relative(sysroot)),
+        sysroot_src: Some(exec_root_relative(sysroot_src)),
+        crates: Vec::new(),
+        workspace: None,
+        pruned_crates: 0,
+    }
+}
+
+/// Renames deps of `crate_id` that share a name, e.g. two deps aliased to the same
+/// name, since rust-analyzer can only resolve one of them. Each dep is paired with
+/// whether its name is an explicit alias. Aliased deps keep their name over deps
+/// named after their crate, and otherwise the first dep (in crate_id order) does.
+/// The others get a numeric suffix.
+fn unique_dependency_names(crate_id: &str, deps: Vec<(Dependency, bool)>) -> Vec<Dependency> {
+    let names: BTreeSet<String> = deps.iter().map(|(dep, _)| dep.name.clone()).collect();
+    if names.len() == deps.len() {
+        return deps.into_iter().map(|(dep, _)| dep).collect();
+    }
+
+    let mut order: Vec<usize> = (0..deps.len()).collect();
+    order.sort_by_key(|&index| !deps[index].1);
+
+    let mut taken: BTreeSet<String> = BTreeSet::new();
+    let mut renamed: BTreeMap<usize, String> = BTreeMap::new();
+    for index in order {
+        let name = &deps[index].0.name;
+        if taken.insert(name.clone()) {
+            continue;
+        }
+        let unique_name = (2..)
+            .map(|n| format!("{name}_{n}"))
+            .find(|candidate| !names.contains(candidate) && !taken.contains(candidate))
+            .expect("unbounded range always yields a name");
+        log::warn!(
+            "Crate {} has several dependencies named `{}`, renaming one to `{}`",
+            crate_id,
+            name,
+            unique_name
+        );
+        taken.insert(unique_name.clone());
+        renamed.insert(index, unique_name);
+    }
+
+    deps.into_iter()
+        .enumerate()
+        .map(|(index, (dep, _))| match renamed.remove(&index) {
+            Some(name) => Dependency { name, ..dep },
+            None => dep,
+        })
+        .collect()
+}
+
+/// Returns the name `dep_crate` is depended on by, which is its display name. Crates
+/// without one (e.g. some generated crates) fall back to their root module's file
+/// name, or `crate_id` as a last resort, so analysis degrades instead of failing.
+fn dependency_name(crate_id: &str, dep_crate: &Crate) -> String {
+    if let Some(display_name) = dep_crate.display_name.as_ref().filter(|n| !n.is_empty()) {
+        return display_name.clone();
+    }
+
+    let name = Path::new(&dep_crate.root_module)
+        .file_stem()
+        .map(|stem| {
+            stem.to_string_lossy()
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect::<String>()
+        })
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| crate_id.to_owned());
+    log::warn!(
+        "Crate {} has no display name, depending on it as `{}`",
+        crate_id,
+        name
+    );
+    name
+}
+
+/// Returns the environment variables to emit for the given crate.
+fn crate_env(spec: &CrateSpec, options: &RustProjectOptions) -> BTreeMap<String, String> {
+    let mut env = spec.env.clone();
+
+    if options.synthesize_manifest_dir && !env.contains_key("CARGO_MANIFEST_DIR") {
+        match manifest_dir(&spec.bazel_target) {
+            Some(manifest_dir) => {
+                log::debug!(
+                    "Synthesized CARGO_MANIFEST_DIR={} for {}",
+                    manifest_dir,
+                    spec.bazel_target
+                );
+                env.insert("CARGO_MANIFEST_DIR".to_owned(), manifest_dir);
+            }
+            None => log::debug!(
+                "Unable to synthesize CARGO_MANIFEST_DIR for {}",
+                spec.bazel_target
+            ),
+        }
+    }
+
+    // rustc runs in the exec root, so relative paths in these are relative to it
+    // rather than to `rust-project.json`.
+    for name in PATH_ENV_VARS {
+        if let Some(value) = env.get_mut(name) {
+            *value = exec_root_relative(value);
+        }
+    }
+
+    env
+}
+
+/// Environment variables holding paths, e.g. for `include!(concat!(env!("OUT_DIR"), ...))`.
+const PATH_ENV_VARS: [&str; 2] = ["CARGO_MANIFEST_DIR", "OUT_DIR"];
+
+/// Returns the package directory of `bazel_target` relative to the exec root, in the
+/// same form `rustc` actions use for `CARGO_MANIFEST_DIR`. Those are derived from the
+/// label's `workspace_root` and `package`, which the label itself spells out, so the
+/// result matches without the aspect reporting the build file's directory.
+fn manifest_dir(bazel_target: &str) -> Option<String> {
+    if bazel_target.is_empty() {
+        return None;
+    }
+    let label = label::analyze(bazel_target).ok()?;
+
+    let workspace_root = match label.repo_name() {
+        Some(repo_name) if !repo_name.is_empty() => format!("external/{repo_name}"),
+        _ => String::new(),
+    };
+
+    // Both the workspace root and package can be empty strings. Avoid
+    // trailing/double slashes in the path.
+    let components = format!("${{pwd}}/{}/{}", workspace_root, label.package()?);
+    Some(
+        components
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>()
+            .join("/"),
+    )
+}
+
+/// Puts a fully resolved project into a canonical form so that regenerating it
+/// produces stable output.
+fn canonicalize_rust_project(project: &mut RustProject, options: &RustProjectOptions) {
+    // cfg order depends on the order crate specs were consolidated in but carries
+    // no meaning, so sort them to avoid churn. Extra cfgs may repeat existing ones.
+    for c in project.crates.iter_mut() {
+        c.cfg.sort();
+        c.cfg.dedup();
+    }
+
+    match options.crate_ordering {
+        CrateOrdering::Resolution => {}
+        CrateOrdering::GroupedByMembership => {
+            let mut order: Vec<usize> = (0..project.crates.len()).collect();
+            order.sort_by(|&a, &b| {
+                let a = &project.crates[a];
+                let b = &project.crates[b];
+                (
+                    a.is_workspace_member != Some(true),
+                    &a.display_name,
+                    &a.root_module,
+                )
+                    .cmp(&(
+                        b.is_workspace_member != Some(true),
+                        &b.display_name,
+                        &b.root_module,
+                    ))
+            });
+            reorder_crates(project, &order);
+        }
+    }
+}
+
+/// Reorders `project.crates` so that the crate previously at `order[i]` is at index `i`,
+/// remapping every dependency index to match.
+fn reorder_crates(project: &mut RustProject, order: &[usize]) {
+    let mut new_index = vec![0; order.len()];
+    for (new, &old) in order.iter().enumerate() {
+        new_index[old] = new;
+    }
+
+    let mut crates: Vec<Option<Crate>> = std::mem::take(&mut project.crates)
+        .into_iter()
+        .map(Some)
+        .collect();
+    project.crates = order
+        .iter()
+        .map(|&old| crates[old].take().expect("each crate is reordered once"))
+        .collect();
+
+    for c in project.crates.iter_mut() {
+        for dep in c.deps.iter_mut() {
+            dep.crate_index = new_index[dep.crate_index];
+        }
+    }
+}
+
+/// The Rust editions accepted as [RustProjectOptions::min_edition].
+const EDITIONS: [&str; 4] = ["2015", "2018", "2021", "2024"];
+
+/// Anchors a relative path at the exec root using the `__EXEC_ROOT__` placeholder.
+/// Paths that are absolute or already start with a placeholder are returned as is.
+fn exec_root_relative(path: &str) -> String {
+    const PLACEHOLDERS: [&str; 3] = ["${pwd}", "__EXEC_ROOT__", "__OUTPUT_BASE__"];
+    if path.is_empty()
+        || Path::new(path).is_absolute()
+        || PLACEHOLDERS.iter().any(|p| path.starts_with(p))
+    {
+        return path.to_owned();
+    }
+    format!("__EXEC_ROOT__/{path}")
+}
+
+/// Anchors a crate's source dir at the exec root if it's relative to it, i.e. under
+/// `external/` or `bazel-out/`. Other relative dirs are relative to the workspace,
+/// like the root modules of workspace crates, and are returned as is so they match
+/// the files the editor opens from the workspace.
+fn anchor_source_dir(dir: &str) -> String {
+    const EXEC_ROOT_DIRS: [&str; 2] = ["external/", "bazel-out/"];
+    if EXEC_ROOT_DIRS.iter().any(|prefix| dir.starts_with(prefix)) {
+        exec_root_relative(dir)
+    } else {
+        dir.to_owned()
+    }
+}
+
+/// Returns the indices of the crates in `rust_project` that contain `file`, using the
+/// same rules as rust-analyzer: a crate contains its root module and every file
+/// under one of its `source.include_dirs` (the root module's directory when none
+/// are given) that isn't under one of its `source.exclude_dirs`.
+///
+/// Paths are compared as they appear in the project, so `file` must be in the same
+/// form as the crates' paths (e.g. prefixed with `__EXEC_ROOT__`).
+pub fn crates_owning_file(rust_project: &RustProject, file: &Path) -> Vec<usize> {
+    rust_project
+        .crates
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| {
+            let root_module = Path::new(&c.root_module);
+            if file == root_module {
+                return true;
+            }
+
+            let included = if c.source.include_dirs.is_empty() {
+                root_module
+                    .parent()
+                    .is_some_and(|dir| file.starts_with(dir))
+            } else {
+                c.source
+                    .include_dirs
+                    .iter()
+                    .any(|dir| file.starts_with(dir))
+            };
+            included
+                && !c
+                    .source
+                    .exclude_dirs
+                    .iter()
+                    .any(|dir| file.starts_with(dir))
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Replaces the exec root and output base placeholders in every crate's `env`
+/// values. Unlike the rest of the project, these are resolved before serialization
+/// so that the resulting paths are JSON-escaped (e.g. Windows `\` separators).
+/// Substitution is purely textual, so the result is the same on every platform.
+pub fn resolve_env_placeholders(
+    rust_project: &mut RustProject,
+    execution_root: &Path,
+    output_base: &Path,
+) {
+    let execution_root = execution_root.to_string_lossy();
+    let output_base = output_base.to_string_lossy();
+    for env in rust_project
+        .crates
+        .iter_mut()
+        .filter_map(|c| c.env.as_mut())
+    {
+        for value in env.values_mut() {
+            *value = resolve_placeholders(value, &execution_root, &output_base);
+        }
+    }
+}
+
+/// Omits the `proc_macro_dylib_path` of proc-macro crates whose dylib hasn't been
+/// built (yet), so rust-analyzer treats them as dummy macros instead of repeatedly
+/// failing to load them. Relative paths are resolved against `workspace`.
+pub fn drop_missing_proc_macro_dylibs(
+    rust_project: &mut RustProject,
+    workspace: &Path,
+    execution_root: &Path,
+    output_base: &Path,
+) {
+    let execution_root = execution_root.to_string_lossy();
+    let output_base = output_base.to_string_lossy();
+    for c in rust_project.crates.iter_mut() {
+        let Some(dylib) = &c.proc_macro_dylib_path else {
+            continue;
+        };
+        if !workspace
+            .join(resolve_placeholders(dylib, &execution_root, &output_base))
+            .exists()
+        {
+            log::debug!("Omitting missing proc-macro dylib {}", dylib);
+            c.proc_macro_dylib_path = None;
+        }
+    }
+}
+
+/// Logs a warning if the `sysroot` or `sysroot_src` of `rust_project` does not
+/// exist, as rust-analyzer will be unable to resolve the standard library. With
+/// `strict`, an error is returned instead.
+pub fn check_sysroot(
+    rust_project: &RustProject,
+    execution_root: &Path,
+    output_base: &Path,
+    strict: bool,
+) -> anyhow::Result<()> {
+    for (field, path) in missing_sysroot_paths(rust_project, execution_root, output_base) {
+        let message = format!(
+            "{field} does not exist: {path}. Build @rules_rust//rust/private:rust_analyzer_detect_sysroot \
+            to fetch the Rust toolchain, or rust-analyzer won't find the standard library."
+        );
+        if strict {
+            return Err(anyhow!(message));
+        }
+        log::warn!("{}", message);
+    }
+    Ok(())
+}
+
+/// Returns the name and resolved path of each of `sysroot` and `sysroot_src` that
+/// is set but isn't a directory.
+fn missing_sysroot_paths(
+    rust_project: &RustProject,
+    execution_root: &Path,
+    output_base: &Path,
+) -> Vec<(&'static str, String)> {
+    [
+        ("sysroot", &rust_project.sysroot),
+        ("sysroot_src", &rust_project.sysroot_src),
+    ]
+    .iter()
+    .filter_map(|&(field, path)| {
+        let path = resolve_placeholders(
+            path.as_deref()?,
+            &execution_root.to_string_lossy(),
+            &output_base.to_string_lossy(),
+        );
+        (!Path::new(&path).is_dir()).then_some((field, path))
+    })
+    .collect()
+}
+
+/// Logs a warning for each `display_name` shared by distinct crates. rust-analyzer
+/// resolves dependencies by name, so these commonly cause navigation to the wrong
+/// crate (e.g. when two versions of the same crate are in the graph).
+pub fn check_duplicate_display_names(rust_project: &RustProject) {
+    for (display_name, crates) in duplicate_display_names(rust_project) {
+        log::warn!(
+            "Multiple crates are named `{}`, dependencies on them may resolve to the wrong crate: {}",
+            display_name,
+            crates.join(", ")
+        );
+    }
+}
+
+/// Logs a warning, or fails if `strict`, for each `root_module` shared by distinct
+/// Bazel targets, e.g. two `rust_library`s over the same `lib.rs`. rust-analyzer
+/// keys some state on the module path and behaves erratically for these. The same
+/// target built for several platforms is fine.
+fn check_duplicate_root_modules(rust_project: &RustProject, strict: bool) -> anyhow::Result<()> {
+    for (root_module, bazel_targets) in duplicate_root_modules(rust_project) {
+        let message = format!(
+            "Multiple crates have the root module `{}`: {}",
+            root_module,
+            bazel_targets.join(", ")
+        );
+        if strict {
+            return Err(anyhow!(message));
+        }
+        log::warn!("{message}");
+    }
+    Ok(())
+}
+
+/// Returns the distinct Bazel targets of the crates sharing each duplicated
+/// `root_module`.
+fn duplicate_root_modules(rust_project: &RustProject) -> BTreeMap<&str, Vec<&str>> {
+    let mut targets_by_root_module: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for c in &rust_project.crates {
+        targets_by_root_module
+            .entry(&c.root_module)
+            .or_default()
+            .insert(&c.bazel_target);
+    }
+
+    targets_by_root_module
+        .into_iter()
+        .filter(|(_, bazel_targets)| bazel_targets.len() > 1)
+        .map(|(root_module, bazel_targets)| (root_module, bazel_targets.into_iter().collect()))
+        .collect()
+}
+
+/// Returns the labels (or root modules, for crates without one) of the crates
+/// sharing each duplicated `display_name`.
+fn duplicate_display_names(rust_project: &RustProject) -> BTreeMap<&str, Vec<&str>> {
+    let mut crates_by_name: BTreeMap<&str, Vec<&Crate>> = BTreeMap::new();
+    for c in &rust_project.crates {
+        if let Some(display_name) = &c.display_name {
+            crates_by_name.entry(display_name).or_default().push(c);
+        }
+    }
+
+    crates_by_name
+        .into_iter()
+        .filter_map(|(display_name, crates)| {
+            let root_modules: BTreeSet<&str> =
+                crates.iter().map(|c| c.root_module.as_str()).collect();
+            if root_modules.len() < 2 {
+                return None;
+            }
+            let crates = crates
+                .iter()
+                .map(|c| {
+                    if c.bazel_target.is_empty() {
+                        c.root_module.as_str()
+                    } else {
+                        c.bazel_target.as_str()
+                    }
+                })
+                .collect();
+            Some((display_name, crates))
+        })
+        .collect()
+}
+
+/// Returns the workspace members in `crates` along with every crate they transitively
+/// depend on. If there are no workspace members, all crates are returned.
+fn prune_orphans<'a>(
+    crates: Vec<&'a CrateSpec>,
+    options: &RustProjectOptions,
+) -> Vec<&'a CrateSpec> {
+    let crates_by_id: BTreeMap<&str, &CrateSpec> =
+        crates.iter().map(|c| (c.crate_id.as_str(), *c)).collect();
+
+    let mut queue: Vec<&str> = crates
+        .iter()
+        .filter(|c| is_workspace_member(c, options))
+        .map(|c| c.crate_id.as_str())
+        .collect();
+    if queue.is_empty() {
+        log::debug!("No workspace members, not pruning orphaned crates");
+        return crates;
+    }
+
+    let mut reachable: BTreeSet<&str> = BTreeSet::new();
+    while let Some(crate_id) = queue.pop() {
+        if !reachable.insert(crate_id) {
+            continue;
+        }
+        if let Some(c) = crates_by_id.get(crate_id) {
+            queue.extend(c.deps.iter().map(String::as_str));
+        }
+    }
+
+    crates
+        .into_iter()
+        .filter(|c| {
+            let keep = reachable.contains(c.crate_id.as_str());
+            if !keep {
+                log::debug!("Pruning orphaned crate {}", c.bazel_target);
+            }
+            keep
+        })
+        .collect()
+}
+
+/// Replaces each crate's `root_module` and `source` dirs with their real paths.
+/// Crates whose root modules resolve to the same file are merged into the first of
+/// them, and dependencies on the merged crates are redirected to it.
+/// Paths that can't be resolved (e.g. they don't exist yet) are left as is.
+pub fn resolve_symlinks(rust_project: &mut RustProject, execution_root: &Path, output_base: &Path) {
+    let execution_root_str = execution_root.to_string_lossy();
+    let output_base_str = output_base.to_string_lossy();
+    let real_path = |path: &str| -> Option<String> {
+        let path = execution_root.join(resolve_placeholders(
+            path,
+            &execution_root_str,
+            &output_base_str,
+        ));
+        std::fs::canonicalize(path)
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned())
+    };
+
+    for c in rust_project.crates.iter_mut() {
+        if let Some(root_module) = real_path(&c.root_module) {
+            c.root_module = root_module;
+        }
+        // Exclude dirs must stay under the include dirs they carve out of.
+        for dir in c
+            .source
+            .include_dirs
+            .iter_mut()
+            .chain(c.source.exclude_dirs.iter_mut())
+        {
+            if let Some(real_dir) = real_path(dir) {
+                *dir = real_dir;
+            }
+        }
+    }
+
+    // Map every crate to the first crate sharing its root module.
+    let mut first_by_root_module: HashMap<String, usize> = HashMap::new();
+    let canonical: Vec<usize> = rust_project
+        .crates
+        .iter()
+        .enumerate()
+        .map(|(index, c)| {
+            *first_by_root_module
+                .entry(c.root_module.clone())
+                .or_insert(index)
+        })
+        .collect();
+    if canonical.iter().enumerate().all(|(index, &c)| index == c) {
+        return;
+    }
+
+    let crates = std::mem::take(&mut rust_project.crates);
+    let mut new_index = vec![0; crates.len()];
+    for (index, c) in crates.into_iter().enumerate() {
+        if canonical[index] == index {
+            new_index[index] = rust_project.crates.len();
+            rust_project.crates.push(c);
+            continue;
+        }
+
+        let kept = &mut rust_project.crates[new_index[canonical[index]]];
+        log::debug!(
+            "Merging {} into {} as both resolve to {}",
+            c.bazel_target,
+            kept.bazel_target,
+            kept.root_module
+        );
+        for dep in c.deps {
+            if !kept.deps.iter().any(|d| d.crate_index == dep.crate_index) {
+                kept.deps.push(dep);
+            }
+        }
+        for cfg in c.cfg {
+            if !kept.cfg.contains(&cfg) {
+                kept.cfg.push(cfg);
+            }
+        }
+        kept.cfg.sort();
+        for flag in c.rustc_flags {
+            if !kept.rustc_flags.contains(&flag) {
+                kept.rustc_flags.push(flag);
+            }
+        }
+        // Like consolidate_crate_specs, keep every dir either crate covers.
+        for (dirs, new_dirs) in [
+            (&mut kept.source.include_dirs, c.source.include_dirs),
+            (&mut kept.source.exclude_dirs, c.source.exclude_dirs),
+        ] {
+            for dir in new_dirs {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        // The kept crate's env wins on conflicts.
+        match (&mut kept.env, c.env) {
+            (Some(kept_env), Some(env)) => {
+                for (key, value) in env {
+                    kept_env.entry(key).or_insert(value);
+                }
+            }
+            (kept_env @ None, env) => *kept_env = env,
+            (Some(_), None) => {}
+        }
+    }
+
+    for (index, c) in rust_project.crates.iter_mut().enumerate() {
+        for dep in c.deps.iter_mut() {
+            dep.crate_index = new_index[canonical[dep.crate_index]];
+        }
+        let mut seen = BTreeSet::from([index]);
+        c.deps.retain(|dep| seen.insert(dep.crate_index));
+    }
+}
+
+/// Returns the crates built for `target`, along with every crate they transitively
+/// depend on. Dependencies are kept regardless of their own target since crates like
+/// proc-macros are built for the exec platform but are still needed to analyze their
+/// dependents.
+fn filter_by_target<'a>(crates: &'a BTreeSet<CrateSpec>, target: &str) -> Vec<&'a CrateSpec> {
+    let crates_by_id: BTreeMap<&str, &CrateSpec> =
+        crates.iter().map(|c| (c.crate_id.as_str(), c)).collect();
+
+    let mut kept: BTreeSet<&str> = BTreeSet::new();
+    let mut queue: Vec<&str> = crates
+        .iter()
+        .filter(|c| c.target.is_empty() || c.target == target)
+        .map(|c| c.crate_id.as_str())
+        .collect();
+
+    while let Some(crate_id) = queue.pop() {
+        if !kept.insert(crate_id) {
+            continue;
+        }
+        if let Some(c) = crates_by_id.get(crate_id) {
+            queue.extend(c.deps.iter().map(String::as_str));
+        }
+    }
+
+    let filtered: Vec<&CrateSpec> = crates
+        .iter()
+        .filter(|c| kept.contains(c.crate_id.as_str()))
+        .collect();
+
+    log::debug!(
+        "Kept {} of {} crates for target {}",
+        filtered.len(),
+        crates.len(),
+        target
+    );
+
+    filtered
+}
+
+/// Returns the first dependency cycle reachable from `current_crate`, ignoring the
+/// (crate_id, dep) edges in `dropped_edges`.
 fn detect_cycle<'a>(
     current_crate: &'a CrateSpec,
     all_crates: &'a BTreeMap<String, &'a CrateSpec>,
+    dropped_edges: &BTreeSet<(String, String)>,
     path: &mut Vec<&'a CrateSpec>,
 ) -> Option<Vec<&'a CrateSpec>> {
     if path
@@ -226,9 +1214,12 @@ fn detect_cycle<'a>(
     path.push(current_crate);
 
     for dep in &current_crate.deps {
+        if dropped_edges.contains(&(current_crate.crate_id.clone(), dep.clone())) {
+            continue;
+        }
         match all_crates.get(dep) {
             Some(dep_crate) => {
-                if let Some(cycle) = detect_cycle(dep_crate, all_crates, path) {
+                if let Some(cycle) = detect_cycle(dep_crate, all_crates, dropped_edges, path) {
                     return Some(cycle);
                 }
             }
@@ -241,12 +1232,181 @@ fn detect_cycle<'a>(
     None
 }
 
-pub fn write_rust_project(
+/// Replaces the sysroot fields of `rust_project` with those found in the existing
+/// `rust-project.json` at `rust_project_path`, if any.
+pub fn preserve_existing_sysroot(
+    rust_project: &mut RustProject,
     rust_project_path: &Path,
+) -> anyhow::Result<()> {
+    let content = match std::fs::read_to_string(rust_project_path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(anyhow!(
+                "Unexpected error reading existing rust-project.json: {}",
+                err
+            ))
+        }
+    };
+
+    let existing: serde_json::Value = match serde_json::from_str(&content) {
+        Ok(existing) => existing,
+        Err(err) => {
+            log::warn!(
+                "Not preserving sysroot from unparsable {}: {}",
+                rust_project_path.display(),
+                err
+            );
+            return Ok(());
+        }
+    };
+
+    for (field, value) in [
+        ("sysroot", &mut rust_project.sysroot),
+        ("sysroot_src", &mut rust_project.sysroot_src),
+    ] {
+        if let Some(existing) = existing.get(field).and_then(|v| v.as_str()) {
+            log::debug!("Preserving existing {}: {}", field, existing);
+            *value = Some(existing.to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the rendered `rust-project.json` to `rust_project_path`, unless the file
+/// already has exactly this content. Rewriting an unchanged file would make
+/// rust-analyzer reload the whole project for nothing. Returns whether the file was
+/// written.
+pub fn write_rust_project(
+    rust_project_path: &Path,
+    execution_root: &Path,
+    output_base: &Path,
+    rust_project: &RustProject,
+) -> anyhow::Result<bool> {
+    let rust_project_content = render_rust_project(execution_root, output_base, rust_project)?;
+
+    if std::fs::read_to_string(rust_project_path)
+        .is_ok_and(|existing| existing == rust_project_content)
+    {
+        log::debug!(
+            "{} is up to date, not rewriting it",
+            rust_project_path.display()
+        );
+        return Ok(false);
+    }
+
+    // Write to a temporary file next to rust-project.json and rename it into place,
+    // so rust-analyzer never observes a missing or partially written file.
+    static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let file_name = rust_project_path.file_name().ok_or_else(|| {
+        anyhow!(
+            "Invalid rust-project.json path: {}",
+            rust_project_path.display()
+        )
+    })?;
+    let temp_path = rust_project_path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let written = std::fs::write(&temp_path, rust_project_content)
+        .and_then(|_| std::fs::rename(&temp_path, rust_project_path));
+    if let Err(err) = written {
+        // Don't leave the temporary file behind. It may not exist if the write failed.
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(anyhow!(
+            "Failed to write {}: {}",
+            rust_project_path.display(),
+            err
+        ));
+    }
+
+    Ok(true)
+}
+
+/// Writes the rendered `rust-project.json` content to stdout as a single JSON object.
+pub fn print_rust_project(
+    execution_root: &Path,
+    output_base: &Path,
+    rust_project: &RustProject,
+) -> anyhow::Result<()> {
+    write_rust_project_to(
+        std::io::stdout().lock(),
+        execution_root,
+        output_base,
+        rust_project,
+    )
+}
+
+/// Writes the rendered `rust-project.json` content to `writer`, e.g. a pipe to an
+/// editor or an in-memory buffer, and flushes it.
+pub fn write_rust_project_to(
+    mut writer: impl Write,
     execution_root: &Path,
     output_base: &Path,
     rust_project: &RustProject,
 ) -> anyhow::Result<()> {
+    let rust_project_content = render_rust_project(execution_root, output_base, rust_project)?;
+
+    writer.write_all(rust_project_content.as_bytes())?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// An entry in the crate to Bazel target mapping written by [write_crate_labels].
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct CrateLabel {
+    /// Index of the crate in the `crates` array of `rust-project.json`.
+    #[serde(rename = "crate")]
+    crate_index: usize,
+
+    /// The display name of the crate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+
+    /// The label of the Bazel target that produced the crate.
+    bazel_target: String,
+}
+
+/// Returns the Bazel target of every crate in `rust_project`, in crate order. Crates
+/// without a label, from specs written by older aspects, are left out.
+pub fn crate_labels(rust_project: &RustProject) -> Vec<CrateLabel> {
+    rust_project
+        .crates
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !c.bazel_target.is_empty())
+        .map(|(crate_index, c)| CrateLabel {
+            crate_index,
+            display_name: c.display_name.clone(),
+            bazel_target: c.bazel_target.clone(),
+        })
+        .collect()
+}
+
+/// Writes the [crate_labels] of `rust_project` to `path` as JSON.
+pub fn write_crate_labels(path: &Path, rust_project: &RustProject) -> anyhow::Result<()> {
+    std::fs::write(
+        path,
+        serde_json::to_string_pretty(&crate_labels(rust_project))?,
+    )?;
+
+    Ok(())
+}
+
+/// Render the `rust-project.json` file and replace the exec root
+/// placeholders with the path to the local exec root. The content ends with a
+/// single newline, as POSIX tools expect of text files. Nothing is written, so
+/// callers may diff or pipe the result.
+pub fn render_rust_project(
+    execution_root: &Path,
+    output_base: &Path,
+    rust_project: &RustProject,
+) -> anyhow::Result<String> {
     let execution_root = execution_root
         .to_str()
         .ok_or_else(|| anyhow!("execution_root is not valid UTF-8"))?;
@@ -255,34 +1415,295 @@ pub fn write_rust_project(
         .to_str()
         .ok_or_else(|| anyhow!("output_base is not valid UTF-8"))?;
 
-    // Try to remove the existing rust-project.json. It's OK if the file doesn't exist.
-    match std::fs::remove_file(rust_project_path) {
-        Ok(_) => {}
-        Err(err) if err.kind() == ErrorKind::NotFound => {}
-        Err(err) => {
-            return Err(anyhow!(
-                "Unexpected error removing old rust-project.json: {}",
-                err
-            ))
-        }
-    }
+    let mut content = resolve_placeholders(
+        &serde_json::to_string_pretty(rust_project)?,
+        execution_root,
+        output_base,
+    );
+    content.push('\n');
+    Ok(content)
+}
 
-    // Render the `rust-project.json` file and replace the exec root
-    // placeholders with the path to the local exec root.
-    let rust_project_content = serde_json::to_string_pretty(rust_project)?
+/// Replaces the exec root and output base placeholders in `value`.
+fn resolve_placeholders(value: &str, execution_root: &str, output_base: &str) -> String {
+    value
         .replace("${pwd}", execution_root)
         .replace("__EXEC_ROOT__", execution_root)
-        .replace("__OUTPUT_BASE__", output_base);
+        .replace("__OUTPUT_BASE__", output_base)
+}
 
-    // Write the new rust-project.json file.
-    std::fs::write(rust_project_path, rust_project_content)?;
+/// A summary of potential quality issues in a generated [RustProject].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProjectSummary {
+    /// The number of crates in the project.
+    pub crates: usize,
 
-    Ok(())
+    /// The number of crates that are workspace members.
+    pub workspace_members: usize,
+
+    /// The number of crates whose root module does not exist.
+    pub missing_root_modules: usize,
+
+    /// The number of proc-macro crates whose dylib does not exist.
+    pub proc_macros_without_dylib: usize,
+
+    /// The number of crates pruned because no workspace member depends on them.
+    pub pruned_crates: usize,
+
+    /// Whether `rust-project.json` already had the generated content and was left
+    /// untouched.
+    pub unchanged: bool,
+}
+
+impl std::fmt::Display for ProjectSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Generated {} crates ({} workspace members); {} crates missing root modules; {} proc-macros without dylib.",
+            self.crates,
+            self.workspace_members,
+            self.missing_root_modules,
+            self.proc_macros_without_dylib
+        )?;
+        if self.pruned_crates > 0 {
+            write!(
+                f,
+                " Pruned {} crates unreachable from workspace members.",
+                self.pruned_crates
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects a [ProjectSummary] for `rust_project`. Relative paths are resolved
+/// against `workspace`, the directory containing `rust-project.json`.
+pub fn summarize_rust_project(
+    rust_project: &RustProject,
+    workspace: &Path,
+    execution_root: &Path,
+    output_base: &Path,
+) -> ProjectSummary {
+    let execution_root = execution_root.to_string_lossy();
+    let output_base = output_base.to_string_lossy();
+    let exists = |path: &str| {
+        workspace
+            .join(resolve_placeholders(path, &execution_root, &output_base))
+            .exists()
+    };
+
+    let mut summary = ProjectSummary {
+        crates: rust_project.crates.len(),
+        pruned_crates: rust_project.pruned_crates,
+        ..ProjectSummary::default()
+    };
+    for c in &rust_project.crates {
+        if c.is_workspace_member == Some(true) {
+            summary.workspace_members += 1;
+        }
+        if !exists(&c.root_module) {
+            log::debug!("Missing root module: {}", c.root_module);
+            summary.missing_root_modules += 1;
+        }
+        if c.is_proc_macro && !c.proc_macro_dylib_path.as_deref().is_some_and(exists) {
+            log::warn!(
+                "Missing proc-macro dylib for {}; macro expansion will be unavailable",
+                c.root_module
+            );
+            summary.proc_macros_without_dylib += 1;
+        }
+    }
+
+    summary
+}
+
+/// Identifies a crate across generations of a [RustProject], since crate indices
+/// shift whenever crates are added or removed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct CrateKey {
+    /// Path to the root module of the crate.
+    pub root_module: String,
+
+    /// Target triple of the crate, distinguishing crates built for several targets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl CrateKey {
+    fn of(c: &Crate) -> Self {
+        CrateKey {
+            root_module: c.root_module.clone(),
+            target: c.target.clone(),
+        }
+    }
+}
+
+/// A dependency of a crate, by name and the root module it resolves to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct DependencyKey {
+    /// The name the dependency is known by.
+    pub name: String,
+
+    /// Path to the root module of the dependency.
+    pub root_module: String,
+}
+
+/// The changes between two generations of a [RustProject], as reported by
+/// [diff_projects].
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ProjectDiff {
+    /// Crates only present in the new project.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added_crates: Vec<CrateKey>,
+
+    /// Crates only present in the old project.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_crates: Vec<CrateKey>,
+
+    /// Crates present in both projects whose deps, cfg or env differ.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed_crates: Vec<CrateDiff>,
+}
+
+impl ProjectDiff {
+    /// Returns true if both projects have the same crates, deps, cfg and env.
+    pub fn is_empty(&self) -> bool {
+        self.added_crates.is_empty()
+            && self.removed_crates.is_empty()
+            && self.changed_crates.is_empty()
+    }
+}
+
+/// The changes to a single crate between two generations of a [RustProject].
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct CrateDiff {
+    /// The crate that changed.
+    #[serde(rename = "crate")]
+    pub crate_key: CrateKey,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added_deps: Vec<DependencyKey>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_deps: Vec<DependencyKey>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub added_cfg: Vec<String>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed_cfg: Vec<String>,
+
+    /// Environment variables that were added, removed or changed, with their old
+    /// and new values. A missing value means the variable wasn't set.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, (Option<String>, Option<String>)>,
+}
+
+impl CrateDiff {
+    fn is_empty(&self) -> bool {
+        self.added_deps.is_empty()
+            && self.removed_deps.is_empty()
+            && self.added_cfg.is_empty()
+            && self.removed_cfg.is_empty()
+            && self.env.is_empty()
+    }
+}
+
+/// Reports what changed between `old` and `new`, e.g. to find out why
+/// rust-analyzer reloaded the workspace. Crates are matched by root module and
+/// target, and deps by name and the root module they resolve to.
+pub fn diff_projects(old: &RustProject, new: &RustProject) -> ProjectDiff {
+    let crates = |project: &RustProject| -> BTreeMap<CrateKey, usize> {
+        project
+            .crates
+            .iter()
+            .enumerate()
+            .map(|(index, c)| (CrateKey::of(c), index))
+            .collect()
+    };
+    let deps = |project: &RustProject, c: &Crate| -> BTreeSet<DependencyKey> {
+        c.deps
+            .iter()
+            .map(|dep| DependencyKey {
+                name: dep.name.clone(),
+                root_module: project.crates[dep.crate_index].root_module.clone(),
+            })
+            .collect()
+    };
+    // Returns the items only in `a` and the items only in `b`.
+    fn difference<T: Ord + Clone>(a: &BTreeSet<T>, b: &BTreeSet<T>) -> (Vec<T>, Vec<T>) {
+        (
+            a.difference(b).cloned().collect(),
+            b.difference(a).cloned().collect(),
+        )
+    }
+
+    let old_crates = crates(old);
+    let new_crates = crates(new);
+
+    let mut diff = ProjectDiff {
+        added_crates: new_crates
+            .keys()
+            .filter(|key| !old_crates.contains_key(key))
+            .cloned()
+            .collect(),
+        removed_crates: old_crates
+            .keys()
+            .filter(|key| !new_crates.contains_key(key))
+            .cloned()
+            .collect(),
+        ..ProjectDiff::default()
+    };
+
+    for (key, &old_index) in &old_crates {
+        let Some(&new_index) = new_crates.get(key) else {
+            continue;
+        };
+        let old_crate = &old.crates[old_index];
+        let new_crate = &new.crates[new_index];
+
+        let (added_deps, removed_deps) = difference(&deps(new, new_crate), &deps(old, old_crate));
+        let (added_cfg, removed_cfg) = difference(
+            &new_crate.cfg.iter().cloned().collect(),
+            &old_crate.cfg.iter().cloned().collect(),
+        );
+
+        let empty = BTreeMap::new();
+        let old_env = old_crate.env.as_ref().unwrap_or(&empty);
+        let new_env = new_crate.env.as_ref().unwrap_or(&empty);
+        let env = old_env
+            .keys()
+            .chain(new_env.keys())
+            .filter(|name| old_env.get(*name) != new_env.get(*name))
+            .map(|name| {
+                (
+                    name.clone(),
+                    (old_env.get(name).cloned(), new_env.get(name).cloned()),
+                )
+            })
+            .collect();
+
+        let crate_diff = CrateDiff {
+            crate_key: key.clone(),
+            added_deps,
+            removed_deps,
+            added_cfg,
+            removed_cfg,
+            env,
+        };
+        if !crate_diff.is_empty() {
+            diff.changed_crates.push(crate_diff);
+        }
+    }
+
+    diff
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aquery::CrateSpecSource;
+    use crate::test_util::TestDir;
 
     /// A simple example with a single crate and no dependencies.
     #[test]
@@ -290,10 +1711,12 @@ mod tests {
         let project = generate_rust_project(
             "sysroot",
             "sysroot_src",
+            "",
             &BTreeSet::from([CrateSpec {
                 aliases: BTreeMap::new(),
                 crate_id: "ID-example".into(),
                 display_name: "example".into(),
+                bazel_target: "//:example".into(),
                 edition: "2018".into(),
                 root_module: "example/lib.rs".into(),
                 is_workspace_member: true,
@@ -304,7 +1727,9 @@ mod tests {
                 env: BTreeMap::new(),
                 target: "x86_64-unknown-linux-gnu".into(),
                 crate_type: "rlib".into(),
+                rustc_flags: vec![],
             }]),
+            &RustProjectOptions::default(),
         )
         .expect("expect success");
 
@@ -321,11 +1746,13 @@ mod tests {
         let project = generate_rust_project(
             "sysroot",
             "sysroot_src",
+            "",
             &BTreeSet::from([
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-example".into(),
                     display_name: "example".into(),
+                    bazel_target: "//:example".into(),
                     edition: "2018".into(),
                     root_module: "example/lib.rs".into(),
                     is_workspace_member: true,
@@ -336,11 +1763,13 @@ mod tests {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-dep_a".into(),
                     display_name: "dep_a".into(),
+                    bazel_target: "//:dep_a".into(),
                     edition: "2018".into(),
                     root_module: "dep_a/lib.rs".into(),
                     is_workspace_member: false,
@@ -351,11 +1780,13 @@ mod tests {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
                 CrateSpec {
                     aliases: BTreeMap::new(),
                     crate_id: "ID-dep_b".into(),
                     display_name: "dep_b".into(),
+                    bazel_target: "//:dep_b".into(),
                     edition: "2018".into(),
                     root_module: "dep_b/lib.rs".into(),
                     is_workspace_member: false,
@@ -366,8 +1797,10 @@ mod tests {
                     env: BTreeMap::new(),
                     target: "x86_64-unknown-linux-gnu".into(),
                     crate_type: "rlib".into(),
+                    rustc_flags: vec![],
                 },
             ]),
+            &RustProjectOptions::default(),
         )
         .expect("expect success");
 
@@ -384,4 +1817,2213 @@ mod tests {
         let c = &project.crates[2];
         assert_eq!(c.display_name, Some("example".into()));
     }
+
+    /// Crates built for other targets are dropped unless a kept crate depends on them.
+    #[test]
+    fn generate_rust_project_target_filter() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-wasm_lib".into(),
+                    display_name: "wasm_lib".into(),
+                    bazel_target: "//:wasm_lib".into(),
+                    edition: "2018".into(),
+                    root_module: "wasm_lib/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::from(["ID-proc_macro".into()]),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "wasm32-unknown-unknown".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-proc_macro".into(),
+                    display_name: "proc_macro".into(),
+                    bazel_target: "//:proc_macro".into(),
+                    edition: "2018".into(),
+                    root_module: "proc_macro/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: Some("proc_macro/libproc_macro.so".into()),
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "proc-macro".into(),
+                    rustc_flags: vec![],
+                },
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-linux_bin".into(),
+                    display_name: "linux_bin".into(),
+                    bazel_target: "//:linux_bin".into(),
+                    edition: "2018".into(),
+                    root_module: "linux_bin/main.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "bin".into(),
+                    rustc_flags: vec![],
+                },
+            ]),
+            &RustProjectOptions {
+                target_filter: Some("wasm32-unknown-unknown".into()),
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        assert_eq!(project.crates.len(), 2);
+        assert_eq!(project.crates[0].display_name, Some("proc_macro".into()));
+        let c = &project.crates[1];
+        assert_eq!(c.display_name, Some("wasm_lib".into()));
+        assert_eq!(c.deps.len(), 1);
+        assert_eq!(c.deps[0].crate_index, 0);
+    }
+
+    /// Sysroot fields are omitted together when rust-analyzer should discover the sysroot.
+    #[test]
+    fn generate_rust_project_discover_sysroot() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        assert_eq!(json["sysroot"], "__EXEC_ROOT__/sysroot");
+        assert_eq!(json["sysroot_src"], "__EXEC_ROOT__/sysroot_src");
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions {
+                discover_sysroot: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        assert!(json.get("sysroot").is_none());
+        assert!(json.get("sysroot_src").is_none());
+    }
+
+    /// Workspace members are listed first when grouping by membership, and
+    /// dependency indices follow the crates they point to.
+    #[test]
+    fn generate_rust_project_grouped_by_membership() {
+        let spec = |name: &str, is_workspace_member: bool, deps: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member,
+            deps: deps.iter().map(|d| format!("ID-{d}")).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("zebra", true, &["external_b"]),
+                spec("alpha", true, &["zebra", "external_a"]),
+                spec("external_b", false, &[]),
+                spec("external_a", false, &["external_b"]),
+            ]),
+            &RustProjectOptions {
+                crate_ordering: CrateOrdering::GroupedByMembership,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        let names: Vec<&str> = project
+            .crates
+            .iter()
+            .map(|c| c.display_name.as_deref().unwrap())
+            .collect();
+        assert_eq!(names, vec!["alpha", "zebra", "external_a", "external_b"]);
+
+        for c in &project.crates {
+            for dep in &c.deps {
+                assert_eq!(
+                    project.crates[dep.crate_index].display_name.as_ref(),
+                    Some(&dep.name)
+                );
+            }
+        }
+        let alpha_deps: Vec<usize> = project.crates[0]
+            .deps
+            .iter()
+            .map(|d| d.crate_index)
+            .collect();
+        assert_eq!(alpha_deps, vec![2, 1]);
+    }
+
+    /// `CARGO_MANIFEST_DIR` is derived from the crate's package unless already set.
+    #[test]
+    fn generate_rust_project_synthesize_manifest_dir() {
+        let spec = |name: &str, bazel_target: &str, env: BTreeMap<String, String>| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: bazel_target.into(),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env,
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("a_local", "//pkg/sub:a_local", BTreeMap::new()),
+                spec("b_external", "@crates__foo-1.0.0//:foo", BTreeMap::new()),
+                spec(
+                    "c_explicit",
+                    "//pkg:c_explicit",
+                    BTreeMap::from([("CARGO_MANIFEST_DIR".into(), "custom".into())]),
+                ),
+            ]),
+            &RustProjectOptions {
+                synthesize_manifest_dir: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        let manifest_dirs: Vec<&str> = project
+            .crates
+            .iter()
+            .map(|c| c.env.as_ref().unwrap()["CARGO_MANIFEST_DIR"].as_str())
+            .collect();
+        assert_eq!(
+            manifest_dirs,
+            vec![
+                "${pwd}/pkg/sub",
+                "${pwd}/external/crates__foo-1.0.0",
+                "__EXEC_ROOT__/custom"
+            ]
+        );
+    }
+
+    #[test]
+    fn summarize_rust_project_counts_missing_files() {
+        let workspace = TestDir::new("summary");
+        std::fs::create_dir_all(workspace.join("present")).unwrap();
+        std::fs::write(workspace.join("present/lib.rs"), "").unwrap();
+
+        let spec = |name: &str, is_workspace_member: bool, dylib: Option<&str>| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: dylib.map(Into::into),
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("present", true, Some("__EXEC_ROOT__/present/lib.rs")),
+                spec("missing", true, None),
+                spec("macro", false, Some("__EXEC_ROOT__/macro/libmacro.so")),
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let summary =
+            summarize_rust_project(&project, &workspace, &workspace, Path::new("output_base"));
+        assert_eq!(
+            summary,
+            ProjectSummary {
+                crates: 3,
+                workspace_members: 2,
+                missing_root_modules: 2,
+                proc_macros_without_dylib: 1,
+                pruned_crates: 0,
+                unchanged: false,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "Generated 3 crates (2 workspace members); 2 crates missing root modules; 1 proc-macros without dylib."
+        );
+    }
+
+    #[test]
+    fn preserve_existing_sysroot_keeps_pinned_fields() {
+        let dir = TestDir::new("preserve");
+        let rust_project_path = dir.join("rust-project.json");
+
+        let generate = || {
+            generate_rust_project(
+                "sysroot",
+                "sysroot_src",
+                "",
+                &BTreeSet::new(),
+                &RustProjectOptions::default(),
+            )
+            .expect("expect success")
+        };
+
+        // Without an existing file the toolchain's sysroot is kept.
+        let mut project = generate();
+        preserve_existing_sysroot(&mut project, &rust_project_path).unwrap();
+        assert_eq!(project.sysroot.as_deref(), Some("__EXEC_ROOT__/sysroot"));
+
+        // Only the fields present in the existing file are preserved.
+        std::fs::write(
+            &rust_project_path,
+            r#"{"sysroot": "/pinned/sysroot", "crates": []}"#,
+        )
+        .unwrap();
+        let mut project = generate();
+        preserve_existing_sysroot(&mut project, &rust_project_path).unwrap();
+        assert_eq!(project.sysroot.as_deref(), Some("/pinned/sysroot"));
+        assert_eq!(
+            project.sysroot_src.as_deref(),
+            Some("__EXEC_ROOT__/sysroot_src")
+        );
+    }
+
+    #[test]
+    fn duplicate_display_names_lists_distinct_crates() {
+        let spec = |crate_id: &str, display_name: &str, bazel_target: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: crate_id.into(),
+            display_name: display_name.into(),
+            bazel_target: bazel_target.into(),
+            edition: "2018".into(),
+            root_module: crate_id.trim_start_matches("ID-").into(),
+            is_workspace_member: false,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("ID-rand-0.7/lib.rs", "rand", "@crates//:rand-0.7"),
+                spec("ID-rand-0.8/lib.rs", "rand", "@crates//:rand-0.8"),
+                spec("ID-serde/lib.rs", "serde", "@crates//:serde"),
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        assert_eq!(
+            duplicate_display_names(&project),
+            BTreeMap::from([("rand", vec!["@crates//:rand-0.7", "@crates//:rand-0.8"])])
+        );
+    }
+
+    #[test]
+    fn duplicate_root_modules_lists_distinct_targets() {
+        let spec = |crate_id: &str, bazel_target: &str, target: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: crate_id.into(),
+            display_name: crate_id.into(),
+            bazel_target: bazel_target.into(),
+            edition: "2018".into(),
+            root_module: "pkg/lib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: target.into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crates = BTreeSet::from([
+            spec("a", "//pkg:a", "x86_64-unknown-linux-gnu"),
+            spec("b", "//pkg:b", "x86_64-unknown-linux-gnu"),
+            spec("b-wasm", "//pkg:b", "wasm32-unknown-unknown"),
+        ]);
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        assert_eq!(
+            duplicate_root_modules(&project),
+            BTreeMap::from([("pkg/lib.rs", vec!["//pkg:a", "//pkg:b"])])
+        );
+
+        let err = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions {
+                strict_root_modules: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Multiple crates have the root module `pkg/lib.rs`: //pkg:a, //pkg:b"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlinks_merges_crates() {
+        let execution_root = TestDir::new("symlinks");
+        std::fs::create_dir_all(execution_root.join("real/vendor")).unwrap();
+        std::fs::write(execution_root.join("real/lib.rs"), "").unwrap();
+        std::os::unix::fs::symlink(execution_root.join("real"), execution_root.join("link"))
+            .unwrap();
+
+        let spec =
+            |name: &str, deps: &[&str], rustc_flags: &[&str], env: &[(&str, &str)]| CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: format!("ID-{name}"),
+                display_name: name.into(),
+                bazel_target: format!("//:{name}"),
+                edition: "2018".into(),
+                root_module: format!("__EXEC_ROOT__/{name}/lib.rs"),
+                is_workspace_member: true,
+                deps: deps.iter().map(|d| format!("ID-{d}")).collect(),
+                proc_macro_dylib_path: None,
+                source: Some(CrateSpecSource {
+                    include_dirs: vec![format!("__EXEC_ROOT__/{name}")],
+                    exclude_dirs: vec![format!("__EXEC_ROOT__/{name}/vendor")],
+                }),
+                cfg: vec!["test".into(), "debug_assertions".into()],
+                env: env
+                    .iter()
+                    .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect(),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: rustc_flags.iter().map(|&f| f.to_owned()).collect(),
+            };
+
+        let mut project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("real", &[], &["-Dwarnings"], &[("A", "real")]),
+                spec(
+                    "link",
+                    &[],
+                    &["-Dwarnings", "-Zshare-generics"],
+                    &[("A", "link"), ("B", "link")],
+                ),
+                spec("user", &["link"], &[], &[]),
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        assert_eq!(project.crates.len(), 3);
+
+        resolve_symlinks(&mut project, &execution_root, Path::new("output_base"));
+
+        let real_root_module = std::fs::canonicalize(execution_root.join("real/lib.rs"))
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert_eq!(project.crates.len(), 2);
+        let real_dir = |dir: &str| {
+            std::fs::canonicalize(execution_root.join(dir))
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        let kept = &project.crates[0];
+        assert_eq!(kept.root_module, real_root_module);
+        // Both crates' dirs resolve to the same real dirs, so they merge into one
+        // include and one exclude dir, keeping the exclusion effective.
+        assert_eq!(kept.source.include_dirs, vec![real_dir("real")]);
+        assert_eq!(kept.source.exclude_dirs, vec![real_dir("real/vendor")]);
+        assert_eq!(kept.rustc_flags, ["-Dwarnings", "-Zshare-generics"]);
+        assert_eq!(
+            kept.env,
+            Some(BTreeMap::from([
+                ("A".to_owned(), "link".to_owned()),
+                ("B".to_owned(), "link".to_owned()),
+            ]))
+        );
+        let user = &project.crates[1];
+        assert_eq!(user.display_name, Some("user".into()));
+        assert_eq!(user.deps.len(), 1);
+        assert_eq!(user.deps[0].crate_index, 0);
+        assert_eq!(user.deps[0].name, "link");
+    }
+
+    #[test]
+    fn generate_rust_project_omit_non_workspace_members() {
+        let spec = |name: &str, is_workspace_member: bool| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crates = BTreeSet::from([spec("external", false), spec("member", true)]);
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        assert_eq!(json["crates"][0]["is_workspace_member"], false);
+        assert_eq!(json["crates"][1]["is_workspace_member"], true);
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions {
+                omit_non_workspace_members: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        assert!(json["crates"][0].get("is_workspace_member").is_none());
+        assert_eq!(json["crates"][1]["is_workspace_member"], true);
+    }
+
+    #[test]
+    fn generate_rust_project_stable_order() {
+        let spec = |name: &str, deps: &[&str], aliases: &[(&str, &str)]| CrateSpec {
+            aliases: aliases
+                .iter()
+                .map(|(dep, alias)| (format!("ID-{dep}"), alias.to_string()))
+                .collect(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2021".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: deps.iter().map(|dep| format!("ID-{dep}")).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        // The aliases make the specs sort differently from their crate_ids.
+        let crates = BTreeSet::from([
+            spec("app", &["util", "base"], &[("util", "utilities")]),
+            spec("util", &["base"], &[]),
+            spec("base", &[], &[]),
+            spec("zeta", &[], &[]),
+        ]);
+
+        let render = || {
+            let project = generate_rust_project(
+                "sysroot",
+                "sysroot_src",
+                "",
+                &crates,
+                &RustProjectOptions::default(),
+            )
+            .expect("expect success");
+            render_rust_project(Path::new("/exec_root"), Path::new("/output_base"), &project)
+                .unwrap()
+        };
+        let first = render();
+        assert_eq!(first, render());
+
+        let json: serde_json::Value = serde_json::from_str(&first).unwrap();
+        let crates = json["crates"].as_array().unwrap();
+        let names: Vec<_> = crates
+            .iter()
+            .map(|c| c["display_name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["base", "util", "zeta", "app"]);
+        assert_eq!(
+            crates[1]["deps"],
+            serde_json::json!([{"crate": 0, "name": "base"}])
+        );
+        assert_eq!(
+            crates[3]["deps"],
+            serde_json::json!([{"crate": 0, "name": "base"}, {"crate": 1, "name": "utilities"}])
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_dep_without_display_name() {
+        let spec =
+            |crate_id: &str, display_name: &str, root_module: &str, deps: &[&str]| CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: crate_id.into(),
+                display_name: display_name.into(),
+                bazel_target: format!("//:{display_name}"),
+                edition: "2021".into(),
+                root_module: root_module.into(),
+                is_workspace_member: true,
+                deps: deps.iter().map(|d| d.to_string()).collect(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![],
+                env: BTreeMap::new(),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("ID-generated", "", "bazel-out/bin/gen-bindings.rs", &[]),
+                spec("ID-app", "app", "app/lib.rs", &["ID-generated"]),
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let app = project
+            .crates
+            .iter()
+            .find(|c| c.display_name.as_deref() == Some("app"))
+            .unwrap();
+        assert_eq!(app.deps.len(), 1);
+        assert_eq!(app.deps[0].name, "gen_bindings");
+    }
+
+    #[test]
+    fn drop_missing_proc_macro_dylibs_keeps_is_proc_macro() {
+        let execution_root = TestDir::new("proc_macro_dylibs");
+        std::fs::write(execution_root.join("libpresent.so"), "").unwrap();
+
+        let spec = |name: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2021".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: Some(format!("__EXEC_ROOT__/lib{name}.so")),
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "proc-macro".into(),
+            rustc_flags: vec![],
+        };
+        let mut project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([spec("present"), spec("missing")]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        drop_missing_proc_macro_dylibs(
+            &mut project,
+            Path::new("/workspace"),
+            &execution_root,
+            Path::new("/output_base"),
+        );
+        let json = serde_json::to_value(&project).unwrap();
+        let crates: BTreeMap<_, _> = json["crates"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| (c["display_name"].as_str().unwrap(), c))
+            .collect();
+        assert_eq!(crates["present"]["is_proc_macro"], true);
+        assert_eq!(
+            crates["present"]["proc_macro_dylib_path"],
+            "__EXEC_ROOT__/libpresent.so"
+        );
+        assert_eq!(crates["missing"]["is_proc_macro"], true);
+        assert!(crates["missing"].get("proc_macro_dylib_path").is_none());
+    }
+
+    #[test]
+    fn generate_rust_project_rustc_flags() {
+        let spec = |name: &str, rustc_flags: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2021".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: rustc_flags.iter().map(|f| f.to_string()).collect(),
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([spec("plain", &[]), spec("flagged", &["-Zshare-generics"])]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        let crates: BTreeMap<_, _> = json["crates"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| (c["display_name"].as_str().unwrap(), c))
+            .collect();
+        assert_eq!(
+            crates["flagged"]["rustc_flags"],
+            serde_json::json!(["-Zshare-generics"])
+        );
+        assert!(crates["plain"].get("rustc_flags").is_none());
+    }
+
+    #[test]
+    fn check_sysroot_reports_missing_paths() {
+        let output_base = TestDir::new("check_sysroot");
+        std::fs::create_dir_all(output_base.join("rust_toolchain/lib/rustlib/src")).unwrap();
+
+        let project = generate_rust_project(
+            "__OUTPUT_BASE__/rust_toolchain",
+            "__OUTPUT_BASE__/rust_toolchain/lib/rustlib/src/library",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let missing = output_base
+            .join("rust_toolchain/lib/rustlib/src/library")
+            .display()
+            .to_string();
+        assert_eq!(
+            missing_sysroot_paths(&project, Path::new("/exec_root"), &output_base),
+            vec![("sysroot_src", missing.clone())]
+        );
+        check_sysroot(&project, Path::new("/exec_root"), &output_base, false).unwrap();
+        let err = check_sysroot(&project, Path::new("/exec_root"), &output_base, true).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with(&format!("sysroot_src does not exist: {missing}.")),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_anchors_path_env_vars() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-example".into(),
+                display_name: "example".into(),
+                bazel_target: "//:example".into(),
+                edition: "2018".into(),
+                root_module: "example/lib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![],
+                env: BTreeMap::from([
+                    (
+                        "OUT_DIR".to_owned(),
+                        "bazel-out/k8-fastbuild/bin/example/build_script.out_dir".to_owned(),
+                    ),
+                    ("CARGO_MANIFEST_DIR".to_owned(), "${pwd}/example".to_owned()),
+                    ("CARGO_PKG_NAME".to_owned(), "example".to_owned()),
+                ]),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            }]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let content =
+            render_rust_project(Path::new("/exec_root"), Path::new("/output_base"), &project)
+                .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(
+            json["crates"][0]["env"],
+            serde_json::json!({
+                "CARGO_MANIFEST_DIR": "/exec_root/example",
+                "CARGO_PKG_NAME": "example",
+                "OUT_DIR": "/exec_root/bazel-out/k8-fastbuild/bin/example/build_script.out_dir",
+            })
+        );
+    }
+
+    #[test]
+    fn diff_projects_reports_changes() {
+        let spec = |name: &str, deps: &[&str], cfg: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: deps.iter().map(|dep| format!("ID-{dep}")).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: cfg.iter().map(|&cfg| cfg.to_owned()).collect(),
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let generate = |specs: Vec<CrateSpec>| {
+            generate_rust_project(
+                "sysroot",
+                "sysroot_src",
+                "",
+                &specs.into_iter().collect(),
+                &RustProjectOptions::default(),
+            )
+            .expect("expect success")
+        };
+        let key = |name: &str| CrateKey {
+            root_module: format!("{name}/lib.rs"),
+            target: Some("x86_64-unknown-linux-gnu".into()),
+        };
+
+        let old = generate(vec![
+            spec("a", &["b"], &["test"]),
+            spec("b", &[], &["test"]),
+            spec("removed", &[], &[]),
+        ]);
+        let new = generate(vec![
+            spec("a", &["b"], &["test", "feature=\"foo\""]),
+            spec("added", &[], &[]),
+            spec("b", &[], &["test"]),
+        ]);
+
+        assert!(diff_projects(&old, &old).is_empty());
+        assert_eq!(
+            diff_projects(&old, &new),
+            ProjectDiff {
+                added_crates: vec![key("added")],
+                removed_crates: vec![key("removed")],
+                changed_crates: vec![CrateDiff {
+                    crate_key: key("a"),
+                    added_deps: vec![],
+                    removed_deps: vec![],
+                    added_cfg: vec!["feature=\"foo\"".into()],
+                    removed_cfg: vec![],
+                    env: BTreeMap::new(),
+                }],
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(diff_projects(&old, &new)).unwrap(),
+            serde_json::json!({
+                "added_crates": [{"root_module": "added/lib.rs", "target": "x86_64-unknown-linux-gnu"}],
+                "removed_crates": [{"root_module": "removed/lib.rs", "target": "x86_64-unknown-linux-gnu"}],
+                "changed_crates": [{
+                    "crate": {"root_module": "a/lib.rs", "target": "x86_64-unknown-linux-gnu"},
+                    "added_cfg": ["feature=\"foo\""],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_non_member_prefixes() {
+        let spec = |name: &str, bazel_target: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: bazel_target.into(),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("local", "//pkg:local"),
+                spec("vendored", "@crates//:vendored"),
+            ]),
+            &RustProjectOptions {
+                non_member_prefixes: vec!["@crates//".into()],
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        let membership: Vec<(&str, Option<bool>)> = project
+            .crates
+            .iter()
+            .map(|c| (c.bazel_target.as_str(), c.is_workspace_member))
+            .collect();
+        assert_eq!(
+            membership,
+            vec![
+                ("//pkg:local", Some(true)),
+                ("@crates//:vendored", Some(false))
+            ]
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_emit_crate_ids() {
+        let crates = BTreeSet::from([CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: "ID-example".into(),
+            display_name: "example".into(),
+            bazel_target: "//:example".into(),
+            edition: "2018".into(),
+            root_module: "example/lib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        }]);
+        let crate_id = |options: &RustProjectOptions| {
+            let project = generate_rust_project("sysroot", "sysroot_src", "", &crates, options)
+                .expect("expect success");
+            serde_json::to_value(&project).unwrap()["crates"][0]
+                .get("crate_id")
+                .cloned()
+        };
+
+        assert_eq!(crate_id(&RustProjectOptions::default()), None);
+        assert_eq!(
+            crate_id(&RustProjectOptions {
+                emit_crate_ids: true,
+                ..RustProjectOptions::default()
+            }),
+            Some(serde_json::json!("ID-example"))
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_no_deps_drops_missing_deps() {
+        let crates = BTreeSet::from([CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: "ID-example".into(),
+            display_name: "example".into(),
+            bazel_target: "//:example".into(),
+            edition: "2018".into(),
+            root_module: "example/lib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::from(["ID-absent".into()]),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        }]);
+
+        assert!(generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions::default()
+        )
+        .is_err());
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions {
+                no_deps: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+        assert_eq!(project.crates.len(), 1);
+        assert!(project.crates[0].deps.is_empty());
+    }
+
+    #[test]
+    fn generate_rust_project_proc_macro_cwd() {
+        let spec = |name: &str, proc_macro_dylib_path: Option<&str>| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//pkg/{name}:{name}"),
+            edition: "2018".into(),
+            root_module: format!("pkg/{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: proc_macro_dylib_path.map(Into::into),
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: if proc_macro_dylib_path.is_some() {
+                "proc-macro".into()
+            } else {
+                "rlib".into()
+            },
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("lib", None),
+                spec(
+                    "macros",
+                    Some("bazel-out/k8-fastbuild/bin/pkg/macros/libmacros.so"),
+                ),
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let json = serde_json::to_value(&project).unwrap();
+        assert_eq!(json["crates"][0]["display_name"], "lib");
+        assert_eq!(json["crates"][0].get("proc_macro_cwd"), None);
+        assert_eq!(json["crates"][1]["display_name"], "macros");
+        assert_eq!(json["crates"][1]["proc_macro_cwd"], "${pwd}/pkg/macros");
+    }
+
+    #[test]
+    fn matches_label_pattern_forms() {
+        for (pattern, label, expected) in [
+            ("//foo/...", "//foo:lib", true),
+            ("//foo/...", "//foo/bar:lib", true),
+            ("//foo/...", "//foobar:lib", false),
+            ("//...", "//foo:lib", true),
+            ("//...", "@crates//:serde", false),
+            ("@crates//...", "@crates//:serde", true),
+            ("//foo:all", "//foo:lib", true),
+            ("//foo:*", "//foo/bar:lib", false),
+            ("//foo:lib", "@//foo:lib", true),
+            ("//foo:lib", "@@//foo:lib", true),
+            ("//foo", "//foo:foo", true),
+            ("//foo", "//foo:lib", false),
+        ] {
+            assert_eq!(
+                matches_label_pattern(pattern, label),
+                expected,
+                "{} {}",
+                pattern,
+                label
+            );
+        }
+    }
+
+    #[test]
+    fn generate_rust_project_exclude_labels() {
+        let spec = |name: &str, deps: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//{name}:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: deps.iter().map(|dep| format!("ID-{dep}")).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("a_leaf", &[]),
+                spec("b_leaf", &[]),
+                spec("c_app", &["a_leaf", "b_leaf"]),
+            ]),
+            &RustProjectOptions {
+                exclude_labels: vec!["//a_leaf/...".into()],
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        let crates: Vec<&str> = project
+            .crates
+            .iter()
+            .map(|c| c.bazel_target.as_str())
+            .collect();
+        assert_eq!(crates, ["//b_leaf:b_leaf", "//c_app:c_app"]);
+        let deps: Vec<(usize, &str)> = project.crates[1]
+            .deps
+            .iter()
+            .map(|dep| (dep.crate_index, dep.name.as_str()))
+            .collect();
+        assert_eq!(deps, [(0, "b_leaf")]);
+        assert_eq!(project.crates[deps[0].0].bazel_target, "//b_leaf:b_leaf");
+    }
+
+    #[test]
+    fn sysroot_only_project_has_no_crates() {
+        let project = sysroot_only_project(
+            "external/rust_linux_x86_64__x86_64-unknown-linux-gnu__stable_tools",
+            "/sysroot/lib/rustlib/src/library",
+        );
+
+        assert_eq!(
+            serde_json::to_value(&project).unwrap(),
+            serde_json::json!({
+                "sysroot": "__EXEC_ROOT__/external/rust_linux_x86_64__x86_64-unknown-linux-gnu__stable_tools",
+                "sysroot_src": "/sysroot/lib/rustlib/src/library",
+                "crates": [],
+            })
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_unique_dependency_names() {
+        let spec = |name: &str, display_name: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: display_name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let user = CrateSpec {
+            aliases: BTreeMap::from([
+                ("ID-a".to_owned(), "rand".to_owned()),
+                ("ID-b".to_owned(), "rand".to_owned()),
+            ]),
+            deps: ["ID-a", "ID-b", "ID-c", "ID-d", "ID-e"]
+                .iter()
+                .map(|&dep| dep.to_owned())
+                .collect(),
+            ..spec("user", "user")
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("a", "rand_core"),
+                spec("b", "rand_legacy"),
+                spec("c", "rand"),
+                spec("d", "serde"),
+                spec("e", "serde"),
+                user,
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let user = project.crates.last().unwrap();
+        assert_eq!(user.bazel_target, "//:user");
+        let deps: Vec<(&str, &str)> = user
+            .deps
+            .iter()
+            .map(|dep| {
+                (
+                    project.crates[dep.crate_index].bazel_target.as_str(),
+                    dep.name.as_str(),
+                )
+            })
+            .collect();
+        // Aliased deps keep their name over those named after their crate.
+        assert_eq!(
+            deps,
+            [
+                ("//:a", "rand"),
+                ("//:b", "rand_2"),
+                ("//:c", "rand_3"),
+                ("//:d", "serde"),
+                ("//:e", "serde_2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_extra_cfgs() {
+        let spec = |name: &str, cfg: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: cfg.iter().map(|&cfg| cfg.to_owned()).collect(),
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("a", &["test"]),
+                spec("b", &["tokio_unstable", "debug_assertions"]),
+            ]),
+            &RustProjectOptions {
+                extra_cfgs: vec!["tokio_unstable".into()],
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        let cfgs: Vec<&[String]> = project.crates.iter().map(|c| c.cfg.as_slice()).collect();
+        assert_eq!(
+            cfgs,
+            [
+                &["test".to_owned(), "tokio_unstable".to_owned()][..],
+                &["debug_assertions".to_owned(), "tokio_unstable".to_owned()][..],
+            ]
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_emit_crate_types() {
+        let crates = BTreeSet::from([CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: "ID-example".into(),
+            display_name: "example".into(),
+            bazel_target: "//:example".into(),
+            edition: "2018".into(),
+            root_module: "example/lib.rs".into(),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "cdylib".into(),
+            rustc_flags: vec![],
+        }]);
+        let crate_type = |options: &RustProjectOptions| {
+            let project = generate_rust_project("sysroot", "sysroot_src", "", &crates, options)
+                .expect("expect success");
+            serde_json::to_value(&project).unwrap()["crates"][0]
+                .get("crate_type")
+                .cloned()
+        };
+
+        assert_eq!(crate_type(&RustProjectOptions::default()), None);
+        assert_eq!(
+            crate_type(&RustProjectOptions {
+                emit_crate_types: true,
+                ..RustProjectOptions::default()
+            }),
+            Some(serde_json::json!("cdylib"))
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_min_edition() {
+        let spec = |name: &str, edition: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: edition.into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crates = BTreeSet::from([spec("legacy", "2015"), spec("modern", "2024")]);
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions {
+                min_edition: Some("2021".into()),
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+        let editions: BTreeMap<_, _> = project
+            .crates
+            .iter()
+            .map(|c| (c.display_name.as_deref().unwrap(), c.edition.as_str()))
+            .collect();
+        assert_eq!(
+            editions,
+            BTreeMap::from([("legacy", "2021"), ("modern", "2024")])
+        );
+
+        let err = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions {
+                min_edition: Some("2020".into()),
+                ..RustProjectOptions::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported minimum edition `2020`, expected one of 2015, 2018, 2021, 2024"
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_sorts_cfg() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-example".into(),
+                display_name: "example".into(),
+                bazel_target: "//:example".into(),
+                edition: "2018".into(),
+                root_module: "example/lib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![
+                    "test".into(),
+                    "feature=\"b\"".into(),
+                    "debug_assertions".into(),
+                    "feature=\"a\"".into(),
+                ],
+                env: BTreeMap::new(),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            }]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        assert_eq!(
+            project.crates[0].cfg,
+            vec![
+                "debug_assertions".to_owned(),
+                "feature=\"a\"".to_owned(),
+                "feature=\"b\"".to_owned(),
+                "test".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_workspace() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "/home/user/my_workspace",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        assert_eq!(json["workspace"], "/home/user/my_workspace");
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        let json = serde_json::to_value(&project).unwrap();
+        assert!(json.get("workspace").is_none());
+    }
+
+    /// Relative sysroot paths are anchored at the exec root, others are kept as is.
+    #[test]
+    fn generate_rust_project_sysroot_paths() {
+        let absolute = std::env::temp_dir().join("sysroot");
+        let absolute = absolute.to_str().unwrap();
+
+        let project = generate_rust_project(
+            absolute,
+            "__OUTPUT_BASE__/external/rust_toolchain/lib/rustlib/src/library",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        assert_eq!(project.sysroot.as_deref(), Some(absolute));
+        assert_eq!(
+            project.sysroot_src.as_deref(),
+            Some("__OUTPUT_BASE__/external/rust_toolchain/lib/rustlib/src/library")
+        );
+
+        let project = generate_rust_project(
+            "rust/toolchain",
+            "rust/toolchain/library",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        assert_eq!(
+            project.sysroot.as_deref(),
+            Some("__EXEC_ROOT__/rust/toolchain")
+        );
+        assert_eq!(
+            project.sysroot_src.as_deref(),
+            Some("__EXEC_ROOT__/rust/toolchain/library")
+        );
+    }
+
+    #[test]
+    fn crate_labels_follow_crate_order() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-example".into(),
+                    display_name: "example".into(),
+                    bazel_target: "//example:example".into(),
+                    edition: "2018".into(),
+                    root_module: "example/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::from(["ID-dep".into()]),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-dep".into(),
+                    display_name: "dep".into(),
+                    bazel_target: "@crates//:dep".into(),
+                    edition: "2018".into(),
+                    root_module: "dep/lib.rs".into(),
+                    is_workspace_member: false,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        assert_eq!(
+            serde_json::to_value(crate_labels(&project)).unwrap(),
+            serde_json::json!([
+                {"crate": 0, "display_name": "dep", "bazel_target": "@crates//:dep"},
+                {"crate": 1, "display_name": "example", "bazel_target": "//example:example"},
+            ])
+        );
+        assert!(serde_json::to_value(&project).unwrap()["crates"][0]
+            .get("bazel_target")
+            .is_none());
+    }
+
+    #[test]
+    fn generate_rust_project_without_bazel_targets() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-unlabeled".into(),
+                    display_name: "unlabeled".into(),
+                    bazel_target: String::new(),
+                    edition: "2018".into(),
+                    root_module: "unlabeled/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-labeled".into(),
+                    display_name: "labeled".into(),
+                    bazel_target: "//:labeled".into(),
+                    edition: "2018".into(),
+                    root_module: "labeled/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+            ]),
+            &RustProjectOptions {
+                synthesize_manifest_dir: true,
+                include_labels: vec!["//:labeled".into()],
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        // Unlabeled crates can't be filtered by label or given a manifest dir.
+        assert_eq!(project.crates.len(), 2);
+        let unlabeled = &project.crates[1];
+        assert_eq!(unlabeled.display_name.as_deref(), Some("unlabeled"));
+        assert!(!unlabeled
+            .env
+            .as_ref()
+            .unwrap()
+            .contains_key("CARGO_MANIFEST_DIR"));
+
+        assert_eq!(
+            serde_json::to_value(crate_labels(&project)).unwrap(),
+            serde_json::json!([
+                {"crate": 0, "display_name": "labeled", "bazel_target": "//:labeled"},
+            ])
+        );
+    }
+
+    /// Nightly-only env and cfgs must reach rust-analyzer untouched.
+    #[test]
+    fn generate_rust_project_nightly_crate() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-nightly".into(),
+                display_name: "nightly".into(),
+                bazel_target: "//:nightly".into(),
+                edition: "2021".into(),
+                root_module: "nightly/lib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![
+                    "test".into(),
+                    "debug_assertions".into(),
+                    "tokio_unstable".into(),
+                    "feature=\"nightly\"".into(),
+                ],
+                env: BTreeMap::from([("RUSTC_BOOTSTRAP".into(), "1".into())]),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            }]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let c = &project.crates[0];
+        assert_eq!(
+            c.env,
+            Some(BTreeMap::from([("RUSTC_BOOTSTRAP".into(), "1".into())]))
+        );
+        assert!(c.cfg.contains(&"tokio_unstable".to_owned()));
+        assert!(c.cfg.contains(&"feature=\"nightly\"".to_owned()));
+    }
+
+    #[test]
+    fn resolve_env_placeholders_escapes_windows_paths() {
+        let mut project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-mylib".into(),
+                display_name: "mylib".into(),
+                bazel_target: "//mylib".into(),
+                edition: "2021".into(),
+                root_module: "mylib/lib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec!["test".into(), "debug_assertions".into()],
+                env: BTreeMap::from([
+                    ("CARGO_MANIFEST_DIR".into(), "${pwd}/mylib".into()),
+                    ("OUT_DIR".into(), "__OUTPUT_BASE__/out".into()),
+                ]),
+                target: "x86_64-pc-windows-msvc".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            }]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        resolve_env_placeholders(
+            &mut project,
+            Path::new(r"C:\exec_root"),
+            Path::new(r"C:\output_base"),
+        );
+        assert_eq!(
+            project.crates[0].env,
+            Some(BTreeMap::from([
+                ("CARGO_MANIFEST_DIR".into(), r"C:\exec_root/mylib".into()),
+                ("OUT_DIR".into(), r"C:\output_base/out".into()),
+            ]))
+        );
+
+        let serialized = serde_json::to_string(&project.crates[0].env).unwrap();
+        assert!(serialized.contains(r#""C:\\exec_root/mylib""#));
+    }
+
+    #[test]
+    fn crates_owning_file_matches_source_dirs() {
+        let spec = |name: &str, source: Option<CrateSpecSource>| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//{name}"),
+            edition: "2021".into(),
+            root_module: format!("__EXEC_ROOT__/{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::new(),
+            proc_macro_dylib_path: None,
+            source,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                spec("a", None),
+                spec(
+                    "b",
+                    Some(CrateSpecSource {
+                        include_dirs: vec![
+                            "__EXEC_ROOT__/b".into(),
+                            "__OUTPUT_BASE__/gen/b".into(),
+                        ],
+                        exclude_dirs: vec!["__EXEC_ROOT__/b/vendor".into()],
+                    }),
+                ),
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let owners = |file: &str| crates_owning_file(&project, Path::new(file));
+        assert_eq!(owners("__EXEC_ROOT__/a/lib.rs"), vec![0]);
+        assert_eq!(owners("__EXEC_ROOT__/a/nested/mod.rs"), vec![0]);
+        assert_eq!(owners("__OUTPUT_BASE__/gen/b/generated.rs"), vec![1]);
+        assert_eq!(owners("__EXEC_ROOT__/b/vendor/dep.rs"), Vec::<usize>::new());
+        assert_eq!(owners("__EXEC_ROOT__/c/lib.rs"), Vec::<usize>::new());
+        // Sibling directories sharing a prefix aren't included.
+        assert_eq!(owners("__EXEC_ROOT__/ab/lib.rs"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn generate_rust_project_prune_orphans() {
+        let spec = |name: &str, is_workspace_member: bool, deps: &[&str]| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("@crates//:{name}"),
+            edition: "2018".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member,
+            deps: deps.iter().map(|d| format!("ID-{d}")).collect(),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+        let crates = BTreeSet::from([
+            spec("app", true, &["serde"]),
+            spec("serde", false, &["serde_derive"]),
+            spec("serde_derive", false, &[]),
+            spec("orphan", false, &["serde"]),
+        ]);
+
+        // The full closure is kept by default.
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        assert_eq!(project.crates.len(), 4);
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &crates,
+            &RustProjectOptions {
+                prune_orphans: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+        let mut names: Vec<&str> = project
+            .crates
+            .iter()
+            .map(|c| c.display_name.as_deref().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["app", "serde", "serde_derive"]);
+        assert_eq!(project.pruned_crates, 1);
+
+        let summary =
+            summarize_rust_project(&project, Path::new("."), Path::new("."), Path::new("."));
+        assert_eq!(summary.pruned_crates, 1);
+        assert!(summary
+            .to_string()
+            .ends_with(" Pruned 1 crates unreachable from workspace members."));
+    }
+
+    #[test]
+    fn render_rust_project_substitutes_placeholders() {
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([CrateSpec {
+                aliases: BTreeMap::new(),
+                crate_id: "ID-example".into(),
+                display_name: "example".into(),
+                bazel_target: "//:example".into(),
+                edition: "2018".into(),
+                root_module: "__EXEC_ROOT__/example/lib.rs".into(),
+                is_workspace_member: true,
+                deps: BTreeSet::new(),
+                proc_macro_dylib_path: None,
+                source: None,
+                cfg: vec![],
+                env: BTreeMap::from([(
+                    "OUT_DIR".to_owned(),
+                    "${pwd}/bazel-out/example".to_owned(),
+                )]),
+                target: "x86_64-unknown-linux-gnu".into(),
+                crate_type: "rlib".into(),
+                rustc_flags: vec![],
+            }]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+
+        let content =
+            render_rust_project(Path::new("/exec_root"), Path::new("/output_base"), &project)
+                .unwrap();
+        assert!(!content.contains("__EXEC_ROOT__"));
+        assert!(!content.contains("${pwd}"));
+        let json: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(json["sysroot"], "/exec_root/sysroot");
+        assert_eq!(
+            json["crates"][0]["root_module"],
+            "/exec_root/example/lib.rs"
+        );
+        assert_eq!(
+            json["crates"][0]["env"]["OUT_DIR"],
+            "/exec_root/bazel-out/example"
+        );
+
+        let mut buffer: Vec<u8> = Vec::new();
+        write_rust_project_to(
+            &mut buffer,
+            Path::new("/exec_root"),
+            Path::new("/output_base"),
+            &project,
+        )
+        .unwrap();
+        let written: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(written, json);
+    }
+
+    #[test]
+    fn write_rust_project_ends_with_newline() {
+        let dir = TestDir::new("newline");
+        let rust_project_path = dir.join("rust-project.json");
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::new(),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        write_rust_project(
+            &rust_project_path,
+            Path::new("/exec_root"),
+            Path::new("/output_base"),
+            &project,
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string(&rust_project_path).unwrap();
+        assert!(content.ends_with("}\n"));
+        assert!(!content.ends_with("\n\n"));
+    }
+
+    #[test]
+    fn write_rust_project_anchors_relative_source_dirs() {
+        let dir = TestDir::new("source_dirs");
+        let rust_project_path = dir.join("rust-project.json");
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-example".into(),
+                    display_name: "example".into(),
+                    bazel_target: "//:example".into(),
+                    edition: "2018".into(),
+                    root_module: "__EXEC_ROOT__/bazel-out/k8-fastbuild/bin/example/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: Some(CrateSpecSource {
+                        include_dirs: vec![
+                            "bazel-out/k8-fastbuild/bin/example".into(),
+                            "external/example/src".into(),
+                            "/abs/example".into(),
+                        ],
+                        exclude_dirs: vec!["__EXEC_ROOT__/example/vendor".into()],
+                    }),
+                    cfg: vec![],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+                // A workspace crate with a build script: its own dir is relative to the
+                // workspace, like its root module, while its OUT_DIR is in the exec root.
+                CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-pkg".into(),
+                    display_name: "pkg".into(),
+                    bazel_target: "//:pkg".into(),
+                    edition: "2018".into(),
+                    root_module: "pkg/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: Some(CrateSpecSource {
+                        include_dirs: vec![
+                            "pkg".into(),
+                            "__EXEC_ROOT__/bazel-out/k8-fastbuild/bin/pkg/_bs.out_dir".into(),
+                        ],
+                        exclude_dirs: vec!["pkg/vendor".into()],
+                    }),
+                    cfg: vec![],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                },
+            ]),
+            &RustProjectOptions::default(),
+        )
+        .expect("expect success");
+        write_rust_project(
+            &rust_project_path,
+            Path::new("/exec_root"),
+            Path::new("/output_base"),
+            &project,
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string(&rust_project_path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&content).unwrap();
+        let example = &json["crates"][0];
+        assert_eq!(
+            example["root_module"],
+            "/exec_root/bazel-out/k8-fastbuild/bin/example/lib.rs"
+        );
+        assert_eq!(
+            example["source"]["include_dirs"],
+            serde_json::json!([
+                "/exec_root/bazel-out/k8-fastbuild/bin/example",
+                "/exec_root/external/example/src",
+                "/abs/example"
+            ])
+        );
+        assert_eq!(
+            example["source"]["exclude_dirs"],
+            serde_json::json!(["/exec_root/example/vendor"])
+        );
+
+        let pkg = &json["crates"][1];
+        assert_eq!(pkg["root_module"], "pkg/lib.rs");
+        assert_eq!(
+            pkg["source"]["include_dirs"],
+            serde_json::json!([
+                "pkg",
+                "/exec_root/bazel-out/k8-fastbuild/bin/pkg/_bs.out_dir"
+            ])
+        );
+        assert_eq!(
+            pkg["source"]["exclude_dirs"],
+            serde_json::json!(["pkg/vendor"])
+        );
+    }
+
+    #[test]
+    fn write_rust_project_skips_unchanged_content() {
+        let dir = TestDir::new("unchanged");
+        let rust_project_path = dir.join("rust-project.json");
+
+        let project = |edition: &str| {
+            generate_rust_project(
+                "sysroot",
+                "sysroot_src",
+                "",
+                &BTreeSet::from([CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: "ID-example".into(),
+                    display_name: "example".into(),
+                    bazel_target: "//:example".into(),
+                    edition: edition.into(),
+                    root_module: "example/lib.rs".into(),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec![],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                }]),
+                &RustProjectOptions::default(),
+            )
+            .expect("expect success")
+        };
+        let write = |project: &RustProject| {
+            write_rust_project(
+                &rust_project_path,
+                Path::new("/exec_root"),
+                Path::new("/output_base"),
+                project,
+            )
+            .unwrap()
+        };
+
+        assert!(write(&project("2018")));
+        let modified = std::fs::metadata(&rust_project_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(!write(&project("2018")));
+        assert_eq!(
+            std::fs::metadata(&rust_project_path)
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
+        );
+        assert!(write(&project("2021")));
+    }
+
+    #[test]
+    fn write_rust_project_concurrently() {
+        let dir = TestDir::new("atomic");
+        let rust_project_path = dir.join("rust-project.json");
+
+        let project = |name: &str| {
+            generate_rust_project(
+                "sysroot",
+                "sysroot_src",
+                "",
+                &BTreeSet::from([CrateSpec {
+                    aliases: BTreeMap::new(),
+                    crate_id: format!("ID-{name}"),
+                    display_name: name.into(),
+                    bazel_target: format!("//:{name}"),
+                    edition: "2021".into(),
+                    root_module: format!("{name}/lib.rs"),
+                    is_workspace_member: true,
+                    deps: BTreeSet::new(),
+                    proc_macro_dylib_path: None,
+                    source: None,
+                    cfg: vec!["test".into(), "debug_assertions".into()],
+                    env: BTreeMap::new(),
+                    target: "x86_64-unknown-linux-gnu".into(),
+                    crate_type: "rlib".into(),
+                    rustc_flags: vec![],
+                }]),
+                &RustProjectOptions::default(),
+            )
+            .expect("expect success")
+        };
+        let projects = [project("a"), project(&"b".repeat(10_000))];
+
+        std::thread::scope(|scope| {
+            for project in &projects {
+                let rust_project_path = &rust_project_path;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write_rust_project(
+                            rust_project_path,
+                            Path::new("/exec_root"),
+                            Path::new("/output_base"),
+                            project,
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for _ in 0..50 {
+                    if let Ok(content) = std::fs::read_to_string(&rust_project_path) {
+                        serde_json::from_str::<serde_json::Value>(&content).unwrap();
+                    }
+                }
+            });
+        });
+
+        let content = std::fs::read_to_string(&rust_project_path).unwrap();
+        serde_json::from_str::<serde_json::Value>(&content).unwrap();
+        // No temporary files are left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn generate_rust_project_reports_cycle() {
+        let spec = |name: &str, dep: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2021".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::from([format!("ID-{dep}")]),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec!["test".into(), "debug_assertions".into()],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let err = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([spec("b", "a"), spec("a", "b")]),
+            &RustProjectOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to make progress on building crate dependency graph: cycle detected: ID-a -> ID-b -> ID-a"
+        );
+    }
+
+    #[test]
+    fn generate_rust_project_break_cycles() {
+        let spec = |name: &str, dep: &str| CrateSpec {
+            aliases: BTreeMap::new(),
+            crate_id: format!("ID-{name}"),
+            display_name: name.into(),
+            bazel_target: format!("//:{name}"),
+            edition: "2021".into(),
+            root_module: format!("{name}/lib.rs"),
+            is_workspace_member: true,
+            deps: BTreeSet::from([format!("ID-{dep}")]),
+            proc_macro_dylib_path: None,
+            source: None,
+            cfg: vec![],
+            env: BTreeMap::new(),
+            target: "x86_64-unknown-linux-gnu".into(),
+            crate_type: "rlib".into(),
+            rustc_flags: vec![],
+        };
+
+        let project = generate_rust_project(
+            "sysroot",
+            "sysroot_src",
+            "",
+            &BTreeSet::from([spec("a", "b"), spec("b", "c"), spec("c", "a")]),
+            &RustProjectOptions {
+                break_cycles: true,
+                ..RustProjectOptions::default()
+            },
+        )
+        .expect("expect success");
+
+        // The dependency of c on a closes the cycle a -> b -> c -> a.
+        let mut edges: Vec<(&str, &str)> = Vec::new();
+        for c in &project.crates {
+            for dep in &c.deps {
+                edges.push((
+                    &c.bazel_target,
+                    &project.crates[dep.crate_index].bazel_target,
+                ));
+            }
+        }
+        assert_eq!(project.crates.len(), 3);
+        assert_eq!(edges, [("//:b", "//:c"), ("//:a", "//:b")]);
+    }
 }